@@ -0,0 +1,95 @@
+use sled::Db;
+
+/// Peer address -> big-endian millisecond timestamp of when it was last seen
+const ADDRESS_BOOK_TREE: &str = "addressbook";
+
+/// Addresses kept at most. Past this the ones seen longest ago are forgotten.
+const MAX_ADDRESSES: usize = 1000;
+
+/// Peer addresses we have heard of, kept across restarts so a node can rejoin the
+/// network without its seeds
+pub struct AddressBook {
+    db: Db,
+}
+
+impl AddressBook {
+    pub fn new(db: Db) -> AddressBook {
+        AddressBook { db }
+    }
+
+    /// Records `addr` as seen at `last_seen`, keeping the later time if it is known.
+    /// A new address may push out the one seen longest ago.
+    pub fn add(&self, addr: &str, last_seen: i64) {
+        let tree = self.db.open_tree(ADDRESS_BOOK_TREE).unwrap();
+        let old = tree
+            .fetch_and_update(addr, |old| {
+                let old_seen = old.map(|bytes| i64::from_be_bytes(bytes.try_into().unwrap()));
+                let seen = old_seen.map_or(last_seen, |old_seen| old_seen.max(last_seen));
+                Some(seen.to_be_bytes().to_vec())
+            })
+            .unwrap();
+        if old.is_some() {
+            return;
+        }
+        while tree.len() > MAX_ADDRESSES {
+            let oldest = tree
+                .iter()
+                .map(|item| item.unwrap())
+                .min_by_key(|(_, seen)| i64::from_be_bytes(seen.as_ref().try_into().unwrap()));
+            match oldest {
+                Some((oldest_addr, _)) => {
+                    let _ = tree.remove(oldest_addr).unwrap();
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub fn contains(&self, addr: &str) -> bool {
+        let tree = self.db.open_tree(ADDRESS_BOOK_TREE).unwrap();
+        tree.contains_key(addr).unwrap()
+    }
+
+    /// Up to `max` addresses with their last-seen times, most recently seen first
+    pub fn get_addresses(&self, max: usize) -> Vec<(String, i64)> {
+        let tree = self.db.open_tree(ADDRESS_BOOK_TREE).unwrap();
+        let mut addresses: Vec<(String, i64)> = tree
+            .iter()
+            .map(|item| {
+                let (addr, seen) = item.unwrap();
+                let addr = String::from_utf8(addr.to_vec()).unwrap();
+                (addr, i64::from_be_bytes(seen.as_ref().try_into().unwrap()))
+            })
+            .collect();
+        addresses.sort_by_key(|(_, last_seen)| std::cmp::Reverse(*last_seen));
+        addresses.truncate(max);
+        addresses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_book_forgets_the_address_seen_longest_ago() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let address_book = AddressBook::new(db);
+        let addr = |i: usize| format!("10.0.{}.{}:2001", i / 256, i % 256);
+        // Seen in reverse, so the first address added is the most recent one
+        for i in 0..MAX_ADDRESSES {
+            address_book.add(addr(i).as_str(), (MAX_ADDRESSES - i) as i64);
+        }
+        // Known addresses only have their time updated
+        address_book.add(addr(0).as_str(), 0);
+        assert_eq!(address_book.get_addresses(usize::MAX).len(), MAX_ADDRESSES);
+
+        address_book.add("10.1.0.0:2001", MAX_ADDRESSES as i64 + 1);
+        let addresses = address_book.get_addresses(usize::MAX);
+        assert_eq!(addresses.len(), MAX_ADDRESSES);
+        assert!(address_book.contains("10.1.0.0:2001"));
+        assert!(address_book.contains(addr(0).as_str()));
+        assert!(!address_book.contains(addr(MAX_ADDRESSES - 1).as_str()));
+        assert_eq!(addresses[0].0, "10.1.0.0:2001");
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, Shutdown, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use log::{error, info};
+
+use crate::{
+    ban_list::BanList,
+    blockchain::Blockchain,
+    node::{Direction, Node},
+    server::{ban_peer, get_peers, DEFAULT_BAN_DURATION},
+};
+
+/// Milliseconds a client has to send its command
+const REQUEST_TIMEOUT: u64 = 5000;
+
+/// Serves the admin API on `addr` from a background thread. A client writes one
+/// command line and reads the reply until the node closes the connection:
+///
+/// - `listpeers`: one `<address> <direction> <rtt ms> <ms since last seen> <ban score>`
+///   line per known peer, with `-` for what is not known or not connected. The ban
+///   score is that of the peer's IP.
+/// - `listbanned`: one `<ip> <unix millis the ban lifts at>` line per ban
+/// - `ban <ip> [seconds]`: bans the IP and drops its sessions
+/// - `unban <ip>`
+///
+/// The API is unauthenticated, so `addr` should be a loopback address.
+pub fn start(addr: &str, blockchain: Blockchain) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Admin API listening on {}", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Admin connection failed: {}", e);
+                    continue;
+                }
+            };
+            // A client that never finishes its command only holds up its own thread
+            let blockchain = blockchain.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle(&blockchain, stream) {
+                    error!("Admin request failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle(blockchain: &Blockchain, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_millis(REQUEST_TIMEOUT)))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let reply = execute(blockchain, line.as_str());
+    stream.write_all(reply.as_bytes())?;
+    stream.shutdown(Shutdown::Both)
+}
+
+fn execute(blockchain: &Blockchain, line: &str) -> String {
+    let ban_list = BanList::new(blockchain.get_db().clone());
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["listpeers"] => get_peers()
+            .iter()
+            .map(|(node, ban_score)| describe_peer(node, *ban_score))
+            .collect(),
+        ["listbanned"] => ban_list
+            .get_bans()
+            .into_iter()
+            .map(|(ip, until)| format!("{} {}\n", ip, until))
+            .collect(),
+        ["ban", ip, rest @ ..] if rest.len() <= 1 => {
+            let ip = match ip.parse::<IpAddr>() {
+                Ok(ip) if ip.is_loopback() => {
+                    return format!("error: loopback address {} can't be banned\n", ip)
+                }
+                Ok(ip) => ip,
+                Err(_) => return format!("error: invalid IP address {}\n", ip),
+            };
+            let duration = match rest.first().map(|seconds| seconds.parse::<i64>()) {
+                None => DEFAULT_BAN_DURATION,
+                Some(Ok(seconds)) if seconds > 0 => seconds.saturating_mul(1000),
+                Some(_) => return format!("error: invalid duration {}\n", rest[0]),
+            };
+            let until = crate::current_timestamp().saturating_add(duration);
+            ban_peer(blockchain, ip, until);
+            format!("banned {} until {}\n", ip, until)
+        }
+        ["unban", ip] => match ip.parse::<IpAddr>() {
+            Ok(ip) if ban_list.unban(ip) => format!("unbanned {}\n", ip),
+            Ok(ip) => format!("error: {} is not banned\n", ip),
+            Err(_) => format!("error: invalid IP address {}\n", ip),
+        },
+        _ => format!("error: unknown command {:?}\n", line.trim()),
+    }
+}
+
+fn describe_peer(node: &Node, ban_score: u32) -> String {
+    let direction = match node.get_connection().map(|c| c.get_direction()) {
+        Some(Direction::Inbound) => "inbound",
+        Some(Direction::Outbound) => "outbound",
+        None => "-",
+    };
+    let rtt = node
+        .get_rtt()
+        .map_or(String::from("-"), |rtt| rtt.as_millis().to_string());
+    let last_seen = node.get_last_seen().map_or(String::from("-"), |seen| {
+        seen.elapsed().as_millis().to_string()
+    });
+    format!(
+        "{} {} {} {} {}\n",
+        node.get_addr(),
+        direction,
+        rtt,
+        last_seen,
+        ban_score
+    )
+}
+
+/// Sends one command to the admin API at `addr` and returns the reply
+pub fn request(addr: &str, command: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
@@ -0,0 +1,58 @@
+use std::net::IpAddr;
+
+use sled::Db;
+
+/// Peer IP -> big-endian millisecond timestamp at which its ban lifts
+const BAN_LIST_TREE: &str = "banlist";
+
+/// Peers refused for misbehaving, kept across restarts
+pub struct BanList {
+    db: Db,
+}
+
+impl BanList {
+    pub fn new(db: Db) -> BanList {
+        BanList { db }
+    }
+
+    /// Refuses `ip` until `until`, replacing any ban already in place
+    pub fn ban(&self, ip: IpAddr, until: i64) {
+        let tree = self.db.open_tree(BAN_LIST_TREE).unwrap();
+        let _ = tree
+            .insert(ip.to_string(), until.to_be_bytes().to_vec())
+            .unwrap();
+    }
+
+    /// Lifts the ban on `ip`. Returns false if it was not banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let tree = self.db.open_tree(BAN_LIST_TREE).unwrap();
+        tree.remove(ip.to_string()).unwrap().is_some()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let tree = self.db.open_tree(BAN_LIST_TREE).unwrap();
+        tree.get(ip.to_string())
+            .unwrap()
+            .map(|until| i64::from_be_bytes(until.as_ref().try_into().unwrap()))
+            .is_some_and(|until| until > crate::current_timestamp())
+    }
+
+    /// Bans still in force with when each lifts, soonest first. Expired ones are
+    /// dropped on the way.
+    pub fn get_bans(&self) -> Vec<(String, i64)> {
+        let tree = self.db.open_tree(BAN_LIST_TREE).unwrap();
+        let now = crate::current_timestamp();
+        let mut bans = vec![];
+        for item in tree.iter() {
+            let (addr, until) = item.unwrap();
+            let until = i64::from_be_bytes(until.as_ref().try_into().unwrap());
+            if until <= now {
+                let _ = tree.remove(addr).unwrap();
+                continue;
+            }
+            bans.push((String::from_utf8(addr.to_vec()).unwrap(), until));
+        }
+        bans.sort_by_key(|(_, until)| *until);
+        bans
+    }
+}
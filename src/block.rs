@@ -1,50 +1,144 @@
 extern crate bincode;
 
-use crate::{proof_of_work::ProofOfWork, transaction::Transaction};
+use crate::{
+    merkle::{self, MerkleBranch},
+    proof_of_work::{self, ProofOfWork},
+    transaction::Transaction,
+};
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 
+const BLOCK_VERSION: u32 = 1;
+
+/// Everything the block hash commits to. Headers are stored apart from the
+/// transactions so that chain walks and PoW checks don't load whole blocks.
 #[derive(Clone, Deserialize, Serialize)]
-pub struct Block {
-    timestamp: i64,
+pub struct BlockHeader {
+    version: u32,
     pre_block_hash: String,
-    hash: String,
-    transactions: Vec<Transaction>,
+    merkle_root: Vec<u8>,
+    timestamp: i64,
+    bits: u32,
     nonce: i64,
     height: usize,
 }
 
+impl BlockHeader {
+    pub fn deserialize(bytes: &[u8]) -> Result<BlockHeader, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap().to_vec()
+    }
+
+    pub fn hash(&self) -> String {
+        ProofOfWork::new_proof_of_work(self.clone()).hash(self.nonce)
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_pre_block_hash(&self) -> String {
+        self.pre_block_hash.clone()
+    }
+
+    pub fn get_merkle_root(&self) -> &[u8] {
+        self.merkle_root.as_slice()
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Compact encoding of the target this block was mined against
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+}
+
+impl From<BlockHeader> for IVec {
+    fn from(header: BlockHeader) -> Self {
+        let bytes = bincode::serialize(&header).unwrap();
+        Self::from(bytes)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Block {
+    header: BlockHeader,
+    transactions: Vec<Transaction>,
+    /// Always recomputed from the header, never trusted from the wire
+    #[serde(skip)]
+    hash: String,
+}
+
 impl Block {
-    pub fn new_block(pre_block_hash: String, transactions: &[Transaction], height: usize) -> Block {
-        let mut block = Block {
-            timestamp: crate::current_timestamp(),
+    pub fn new_block(
+        pre_block_hash: String,
+        transactions: &[Transaction],
+        height: usize,
+        bits: u32,
+        timestamp: i64,
+    ) -> Block {
+        let mut header = BlockHeader {
+            version: BLOCK_VERSION,
             pre_block_hash,
-            hash: String::new(),
-            transactions: transactions.to_vec(),
+            merkle_root: merkle::merkle_root(get_txids(transactions).as_slice()),
+            timestamp,
+            bits,
             nonce: 0,
             height,
         };
-        let pow = ProofOfWork::new_proof_of_work(block.clone());
+        let pow = ProofOfWork::new_proof_of_work(header.clone());
         let (nonce, hash) = pow.run();
-        block.nonce = nonce;
-        block.hash = hash;
-        block
+        header.nonce = nonce;
+        Block {
+            header,
+            transactions: transactions.to_vec(),
+            hash,
+        }
+    }
+
+    /// Reassembles a block from a stored header and body
+    pub fn from_parts(header: BlockHeader, transactions: Vec<Transaction>) -> Block {
+        let hash = header.hash();
+        Block {
+            header,
+            transactions,
+            hash,
+        }
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Block {
-        bincode::deserialize(bytes).unwrap()
+    pub fn deserialize(bytes: &[u8]) -> Result<Block, bincode::Error> {
+        let mut block: Block = bincode::deserialize(bytes)?;
+        block.hash = block.header.hash();
+        Ok(block)
     }
 
     pub fn serialize(&self) -> Vec<u8> {
         bincode::serialize(self).unwrap().to_vec()
     }
 
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_transactions(&self) -> &[Transaction] {
         self.transactions.as_slice()
     }
 
     pub fn get_pre_block_hash(&self) -> String {
-        self.pre_block_hash.clone()
+        self.header.get_pre_block_hash()
     }
 
     pub fn get_hash(&self) -> &str {
@@ -56,27 +150,53 @@ impl Block {
     }
 
     pub fn get_timestamp(&self) -> i64 {
-        self.timestamp
+        self.header.get_timestamp()
+    }
+
+    pub fn get_nonce(&self) -> i64 {
+        self.header.get_nonce()
     }
 
     pub fn get_height(&self) -> usize {
-        self.height
+        self.header.get_height()
+    }
+
+    /// Compact encoding of the target this block was mined against
+    pub fn get_bits(&self) -> u32 {
+        self.header.get_bits()
     }
 
+    /// Merkle root of the block's transaction ids, as it should appear in the header
     pub fn hash_transactions(&self) -> Vec<u8> {
-        let mut txhashs = vec![];
-        for transaction in &self.transactions {
-            txhashs.extend(transaction.get_id());
-        }
-        sha256::digest(txhashs.as_slice()).into()
+        merkle::merkle_root(get_txids(&self.transactions).as_slice())
+    }
+
+    /// Proof that the transaction `txid` is part of this block
+    pub fn get_merkle_branch(&self, txid: &[u8]) -> Option<MerkleBranch> {
+        let txids = get_txids(&self.transactions);
+        let index = txids.iter().position(|id| id.as_slice() == txid)?;
+        merkle::merkle_branch(txids.as_slice(), index)
     }
 
     pub fn generate_genesis_block(transaction: &Transaction) -> Block {
         let transactions = vec![transaction.clone()];
-        return Block::new_block(String::from("None"), &transactions, 0);
+        Block::new_block(
+            String::from("None"),
+            &transactions,
+            0,
+            proof_of_work::initial_bits(),
+            crate::current_timestamp(),
+        )
     }
 }
 
+fn get_txids(transactions: &[Transaction]) -> Vec<Vec<u8>> {
+    transactions
+        .iter()
+        .map(|transaction| transaction.get_id().to_vec())
+        .collect()
+}
+
 impl From<Block> for IVec {
     fn from(b: Block) -> Self {
         let bytes = bincode::serialize(&b).unwrap();
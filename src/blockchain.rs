@@ -1,42 +1,85 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env::current_dir,
-    sync::{Arc, RwLock},
+    fmt,
+    sync::{Arc, Mutex, RwLock},
 };
 
 use data_encoding::HEXLOWER;
-use sled::{transaction::TransactionResult, Db, Tree};
+use log::info;
+use num::{bigint::Sign, BigInt};
+use sled::{transaction::TransactionResult, Db, Transactional};
 
 use crate::{
-    block::Block,
-    transaction::{TXOutput, Transaction},
+    block::{Block, BlockHeader},
+    proof_of_work::{self, ProofOfWork, RETARGET_INTERVAL},
+    transaction::{Transaction, SUBSIDY},
+    utxo_set::{self, UTXOEntry, UTXOSet},
 };
 
 const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
+/// Block hash -> serialized `BlockHeader`, plus the tip hash
 const BLOCKS_TREE: &str = "blocks";
+/// Block hash -> the block's transactions
+const BLOCK_BODIES_TREE: &str = "blockbodies";
+/// Cumulative proof of work up to and including each stored block, on every branch
+const CHAIN_WORK_TREE: &str = "chainwork";
+/// txid -> (block hash, position in block) for transactions on the best chain
+const TX_INDEX_TREE: &str = "txindex";
+/// Big-endian height -> block hash for blocks on the best chain
+const HEIGHT_INDEX_TREE: &str = "heightindex";
+
+/// Number of most recent best-chain blocks listed one by one in a block locator
+const LOCATOR_DENSE_SPAN: usize = 10;
+
+/// Number of preceding blocks whose median timestamp a new block must exceed
+const MEDIAN_TIME_SPAN: usize = 11;
+/// How far ahead of our own clock a block timestamp may be, in milliseconds
+const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60 * 1000;
 
 #[derive(Clone)]
 pub struct Blockchain {
     tip_hash: Arc<RwLock<String>>,
+    /// Serializes `add_block`, since blocks arrive on many connections at once
+    update_lock: Arc<Mutex<()>>,
     db: Db,
 }
 
 impl Blockchain {
     pub fn create_blockchain(genesis_address: &str) -> Blockchain {
         let db = sled::open(current_dir().unwrap().join("data")).unwrap();
+        Self::create_with_db(db, genesis_address)
+    }
+
+    /// A chain in a throwaway database that is deleted when dropped
+    #[cfg(test)]
+    pub fn create_temporary(genesis_address: &str) -> Blockchain {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Self::create_with_db(db, genesis_address)
+    }
+
+    fn create_with_db(db: Db, genesis_address: &str) -> Blockchain {
         let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
         let data = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap();
-        let tip_hash;
-        if data.is_none() {
-            let coinbase_tx = Transaction::new_coinbase_tx(genesis_address);
-            let block = Block::generate_genesis_block(&coinbase_tx);
-            Self::update_blocks_tree(&blocks_tree, &block);
-            tip_hash = String::from(block.get_hash());
-        } else {
-            tip_hash = String::from_utf8(data.unwrap().to_vec()).unwrap();
-        }
+        let tip_hash = match data {
+            Some(data) => String::from_utf8(data.to_vec()).unwrap(),
+            None => {
+                let coinbase_tx = Transaction::new_coinbase_tx(genesis_address);
+                let block = Block::generate_genesis_block(&coinbase_tx);
+                Self::update_blocks_tree(&db, &block);
+                let _ = blocks_tree
+                    .insert(TIP_BLOCK_HASH_KEY, block.get_hash())
+                    .unwrap();
+                let genesis_work = proof_of_work::block_work(block.get_bits());
+                Self::update_chain_work_tree(&db, block.get_hash(), &genesis_work);
+                Self::update_tx_index_tree(&db, &block);
+                Self::update_height_index_tree(&db, &block);
+                String::from(block.get_hash())
+            }
+        };
         Blockchain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
+            update_lock: Arc::new(Mutex::new(())),
             db,
         }
     }
@@ -51,6 +94,7 @@ impl Blockchain {
         let tip_hash = String::from_utf8(tip_bytes.to_vec()).unwrap();
         Blockchain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
+            update_lock: Arc::new(Mutex::new(())),
             db,
         }
     }
@@ -72,23 +116,38 @@ impl Blockchain {
         BlockchainIterator::new(self.get_tip_hash(), self.db.clone())
     }
 
-    pub fn mine_block(&self, transactions: &[Transaction]) -> Block {
-        for transaction in transactions {
-            if !transaction.verify(self) {
-                panic!("ERROR: Invalid transaction");
-            }
-        }
-        let best_height = self.get_best_height();
-        let block = Block::new_block(self.get_tip_hash(), transactions, best_height + 1);
-        let block_hash = block.get_hash();
-
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        Self::update_blocks_tree(&blocks_tree, &block);
-        self.set_tip_hash(block_hash);
-        block
+    /// Mines `transactions` on top of the tip. The body is checked like a received
+    /// block's before it is added.
+    pub fn mine_block(&self, transactions: &[Transaction]) -> Result<Block, BlockValidationError> {
+        let tip_header = self
+            .get_block_header(self.get_tip_hash().as_bytes())
+            .expect("The tip hash is valid");
+        let bits = self.get_next_bits(&tip_header);
+        // A clock behind the chain would otherwise mine a block validate_header refuses
+        let timestamp = crate::current_timestamp().max(self.get_median_time_past(&tip_header) + 1);
+        let block = Block::new_block(
+            self.get_tip_hash(),
+            transactions,
+            tip_header.get_height() + 1,
+            bits,
+            timestamp,
+        );
+        self.validate_transactions(&block)?;
+        self.add_block(&block)?;
+        Ok(block)
     }
 
     pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
+        let tx_index_tree = self.db.open_tree(TX_INDEX_TREE).unwrap();
+        if let Some(location_bytes) = tx_index_tree.get(txid).unwrap() {
+            let (block_hash, position): (String, usize) =
+                bincode::deserialize(location_bytes.as_ref()).expect("unable to deserialize");
+            if let Some(block) = self.get_block(block_hash.as_bytes()) {
+                return block.get_transactions().get(position).cloned();
+            }
+        }
+
+        // Chains stored before the index existed are only reachable by walking back
         let mut iterator = self.iterator();
         loop {
             let option = iterator.next();
@@ -106,97 +165,574 @@ impl Blockchain {
     }
 
     pub fn get_best_height(&self) -> usize {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let tip_block_bytes = block_tree
-            .get(self.get_tip_hash())
-            .unwrap()
-            .expect("The tip hash is valid");
-        let tip_block = Block::deserialize(tip_block_bytes.as_ref());
-        tip_block.get_height()
+        self.get_block_header(self.get_tip_hash().as_bytes())
+            .expect("The tip hash is valid")
+            .get_height()
     }
 
     pub fn get_block(&self, block_hash: &[u8]) -> Option<Block> {
+        load_block(&self.db, block_hash)
+    }
+
+    /// Whether the body of the block is stored, not just its header
+    pub fn has_block(&self, block_hash: &[u8]) -> bool {
+        let bodies_tree = self.db.open_tree(BLOCK_BODIES_TREE).unwrap();
+        bodies_tree.contains_key(block_hash).unwrap()
+    }
+
+    /// Reads only the header of a stored block, without its transactions
+    pub fn get_block_header(&self, block_hash: &[u8]) -> Option<BlockHeader> {
         let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        block_tree.get(block_hash).unwrap().map(|block_bytes| {
-            let block = Block::deserialize(block_bytes.as_ref());
-            block
+        block_tree
+            .get(block_hash)
+            .unwrap()
+            .map(|header_bytes| BlockHeader::deserialize(header_bytes.as_ref()).unwrap())
+    }
+
+    /// The best-chain block at `height`
+    pub fn get_block_by_height(&self, height: usize) -> Option<Block> {
+        let block_hash = self.get_block_hash_by_height(height)?;
+        self.get_block(block_hash.as_slice())
+    }
+
+    fn get_block_hash_by_height(&self, height: usize) -> Option<Vec<u8>> {
+        let height_index_tree = self.db.open_tree(HEIGHT_INDEX_TREE).unwrap();
+        height_index_tree
+            .get((height as u64).to_be_bytes())
+            .unwrap()
+            .map(|hash| hash.to_vec())
+    }
+
+    /// Best-chain block hashes for heights in `start..end`
+    pub fn get_block_hashes_range(&self, start: usize, end: usize) -> Vec<Vec<u8>> {
+        let height_index_tree = self.db.open_tree(HEIGHT_INDEX_TREE).unwrap();
+        let start_key = (start as u64).to_be_bytes();
+        let end_key = (end as u64).to_be_bytes();
+        let mut hashes = vec![];
+        for item in height_index_tree.range(start_key..end_key) {
+            let (_, hash) = item.unwrap();
+            hashes.push(hash.to_vec());
+        }
+        hashes
+    }
+
+    /// Best-chain hashes from the tip back to genesis: the most recent ones one by one,
+    /// then with the step doubling each time, so a peer can find where our chains fork
+    pub fn get_block_locator(&self) -> Vec<Vec<u8>> {
+        let mut locator = vec![];
+        let mut height = self.get_best_height();
+        let mut step = 1;
+        loop {
+            if let Some(hash) = self.get_block_hash_by_height(height) {
+                locator.push(hash);
+            }
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= LOCATOR_DENSE_SPAN {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Height of the first locator entry that is on our best chain
+    fn find_locator_fork(&self, locator: &[Vec<u8>]) -> Option<usize> {
+        locator.iter().find_map(|hash| {
+            let header = self.get_block_header(hash)?;
+            let best_hash = self.get_block_hash_by_height(header.get_height())?;
+            best_hash.eq(hash).then_some(header.get_height())
         })
     }
 
-    pub fn get_block_hashes(&self) -> Vec<Vec<u8>> {
-        let iterator = self.iterator();
-        let mut blocks = vec![];
-        for block in iterator {
-            blocks.push(block.get_hash_bytes());
+    /// Up to `max` best-chain hashes following the point where `locator` forks from
+    /// our chain, or from genesis when none of it is known. The list ends early at
+    /// `stop_hash` when that block is reached; an empty `stop_hash` never matches.
+    pub fn get_block_hashes_after(
+        &self,
+        locator: &[Vec<u8>],
+        stop_hash: &[u8],
+        max: usize,
+    ) -> Vec<Vec<u8>> {
+        let start = match self.find_locator_fork(locator) {
+            Some(height) => height + 1,
+            None => 0,
+        };
+        let mut hashes = self.get_block_hashes_range(start, start + max);
+        if let Some(pos) = hashes.iter().position(|hash| hash.eq(stop_hash)) {
+            hashes.truncate(pos + 1);
         }
-        blocks
+        hashes
+    }
+
+    /// Headers for `get_block_hashes_after`, without a stop hash
+    pub fn get_headers_after(&self, locator: &[Vec<u8>], max: usize) -> Vec<BlockHeader> {
+        self.get_block_hashes_after(locator, &[], max)
+            .iter()
+            .map(|hash| {
+                self.get_block_header(hash)
+                    .expect("The height index only holds stored blocks")
+            })
+            .collect()
+    }
+
+    /// Iterates the best chain from genesis up to the tip
+    pub fn forward_iterator(&self) -> BlockchainForwardIterator {
+        BlockchainForwardIterator::new(self.clone())
+    }
+
+    fn update_tx_index_tree(db: &Db, block: &Block) {
+        let tx_index_tree = db.open_tree(TX_INDEX_TREE).unwrap();
+        for (position, tx) in block.get_transactions().iter().enumerate() {
+            let location = (block.get_hash(), position);
+            let location_bytes = bincode::serialize(&location).expect("unable to serialize");
+            let _ = tx_index_tree.insert(tx.get_id(), location_bytes).unwrap();
+        }
+    }
+
+    fn remove_from_tx_index_tree(db: &Db, block: &Block) {
+        let tx_index_tree = db.open_tree(TX_INDEX_TREE).unwrap();
+        for tx in block.get_transactions() {
+            let _ = tx_index_tree.remove(tx.get_id()).unwrap();
+        }
+    }
+
+    fn update_height_index_tree(db: &Db, block: &Block) {
+        let height_index_tree = db.open_tree(HEIGHT_INDEX_TREE).unwrap();
+        let height_key = (block.get_height() as u64).to_be_bytes();
+        let _ = height_index_tree
+            .insert(height_key, block.get_hash())
+            .unwrap();
+    }
+
+    fn remove_from_height_index_tree(db: &Db, block: &Block) {
+        let height_index_tree = db.open_tree(HEIGHT_INDEX_TREE).unwrap();
+        let height_key = (block.get_height() as u64).to_be_bytes();
+        let _ = height_index_tree.remove(height_key).unwrap();
+    }
+
+    fn update_chain_work_tree(db: &Db, block_hash: &str, chain_work: &BigInt) {
+        let chain_work_tree = db.open_tree(CHAIN_WORK_TREE).unwrap();
+        let (_, work_bytes) = chain_work.to_bytes_be();
+        let _ = chain_work_tree.insert(block_hash, work_bytes).unwrap();
+    }
+
+    /// Total work of the branch ending at `block_hash`
+    pub fn get_chain_work(&self, block_hash: &str) -> BigInt {
+        let chain_work_tree = self.db.open_tree(CHAIN_WORK_TREE).unwrap();
+        let work_bytes = chain_work_tree
+            .get(block_hash)
+            .unwrap()
+            .expect("Every stored block has its chain work");
+        BigInt::from_bytes_be(Sign::Plus, work_bytes.as_ref())
     }
 
-    fn update_blocks_tree(blocks_tree: &Tree, block: &Block) {
+    /// Stores the header and the body of a block under its hash
+    fn update_blocks_tree(db: &Db, block: &Block) {
+        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
+        let bodies_tree = db.open_tree(BLOCK_BODIES_TREE).unwrap();
         let block_hash = block.get_hash();
-        let _: TransactionResult<(), ()> = blocks_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block_hash, block.clone());
-            let _ = tx_db.insert(TIP_BLOCK_HASH_KEY, block_hash);
-            Ok(())
-        });
+        let body_bytes = bincode::serialize(block.get_transactions()).expect("unable to serialize");
+        let _: TransactionResult<(), ()> =
+            (&blocks_tree, &bodies_tree).transaction(|(headers, bodies)| {
+                let _ = headers.insert(block_hash, block.get_header().clone())?;
+                let _ = bodies.insert(block_hash, body_bytes.as_slice())?;
+                Ok(())
+            });
+    }
+
+    /// The compact target a child of `parent` must be mined against. It only changes
+    /// on `RETARGET_INTERVAL` boundaries, based on how long the previous interval took.
+    pub fn get_next_bits(&self, parent: &BlockHeader) -> u32 {
+        let height = parent.get_height() + 1;
+        if !height.is_multiple_of(RETARGET_INTERVAL) {
+            return parent.get_bits();
+        }
+        let mut first = parent.clone();
+        while first.get_height() > height - RETARGET_INTERVAL {
+            first = self
+                .get_block_header(first.get_pre_block_hash().as_bytes())
+                .expect("The parent chain is complete");
+        }
+        let actual_timespan = parent.get_timestamp() - first.get_timestamp();
+        proof_of_work::retarget(parent.get_bits(), actual_timespan)
+    }
+
+    /// Median timestamp of `parent` and up to `MEDIAN_TIME_SPAN - 1` of its ancestors
+    pub fn get_median_time_past(&self, parent: &BlockHeader) -> i64 {
+        let mut timestamps = vec![parent.get_timestamp()];
+        let mut current = parent.clone();
+        while timestamps.len() < MEDIAN_TIME_SPAN && current.get_height() > 0 {
+            current = match self.get_block_header(current.get_pre_block_hash().as_bytes()) {
+                Some(header) => header,
+                None => break,
+            };
+            timestamps.push(current.get_timestamp());
+        }
+        timestamps.sort();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// The consensus checks that need only the header: proof of work and how it
+    /// follows its parent header. Used on its own during headers-first sync.
+    pub fn validate_header(&self, header: &BlockHeader) -> Result<(), BlockValidationError> {
+        if !ProofOfWork::new_proof_of_work(header.clone()).validate() {
+            return Err(BlockValidationError::InvalidProofOfWork);
+        }
+        let parent = self
+            .get_block_header(header.get_pre_block_hash().as_bytes())
+            .ok_or_else(|| BlockValidationError::UnknownParent(header.get_pre_block_hash()))?;
+        if header.get_height() != parent.get_height() + 1 {
+            return Err(BlockValidationError::InvalidHeight {
+                expected: parent.get_height() + 1,
+                found: header.get_height(),
+            });
+        }
+        let expected_bits = self.get_next_bits(&parent);
+        if header.get_bits() != expected_bits {
+            return Err(BlockValidationError::InvalidBits {
+                expected: expected_bits,
+                found: header.get_bits(),
+            });
+        }
+        let median_time_past = self.get_median_time_past(&parent);
+        if header.get_timestamp() <= median_time_past {
+            return Err(BlockValidationError::TimestampTooOld {
+                median_time_past,
+                found: header.get_timestamp(),
+            });
+        }
+        let max_timestamp = crate::current_timestamp() + MAX_FUTURE_BLOCK_TIME;
+        if header.get_timestamp() > max_timestamp {
+            return Err(BlockValidationError::TimestampTooNew {
+                max: max_timestamp,
+                found: header.get_timestamp(),
+            });
+        }
+        Ok(())
     }
 
-    pub fn add_block(&self, block: &Block) {
+    /// Stores a header that passed `validate_header` so its body can be fetched
+    /// later. The best chain only moves once the body is given to `add_block`.
+    pub fn add_header(&self, header: &BlockHeader) {
+        let block_hash = header.hash();
         let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        if let Some(_) = block_tree.get(block.get_hash()).unwrap() {
+        if block_tree.contains_key(block_hash.as_str()).unwrap() {
             return;
         }
-        let _: TransactionResult<(), ()> = block_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block.get_hash(), block.serialize()).unwrap();
-            let tip_block_bytes = tx_db
-                .get(self.get_tip_hash())
-                .unwrap()
-                .expect("The tip hash is not valid");
-            let tip_block = Block::deserialize(&tip_block_bytes);
-            if block.get_height() > tip_block.get_height() {
-                let _ = tx_db.insert(TIP_BLOCK_HASH_KEY, block.get_hash()).unwrap();
-                self.set_tip_hash(block.get_hash());
+        let parent_work = self.get_chain_work(header.get_pre_block_hash().as_str());
+        let chain_work = parent_work + proof_of_work::block_work(header.get_bits());
+        let _ = block_tree
+            .insert(block_hash.as_str(), header.clone())
+            .unwrap();
+        Self::update_chain_work_tree(&self.db, block_hash.as_str(), &chain_work);
+    }
+
+    /// Runs every consensus check on a block received from a peer. Blocks should only
+    /// be passed to `add_block` once this succeeds. Its parent's body must be stored
+    /// already, since the transactions are checked against the branch it extends.
+    pub fn validate_block(&self, block: &Block) -> Result<(), BlockValidationError> {
+        if self.has_block(block.get_hash().as_bytes()) {
+            return Err(BlockValidationError::AlreadyKnown);
+        }
+        self.validate_header(block.get_header())?;
+        // Checked before the parent, so a body that doesn't match its header is
+        // never held as an orphan
+        if block.get_header().get_merkle_root() != block.hash_transactions().as_slice() {
+            return Err(BlockValidationError::InvalidMerkleRoot);
+        }
+        if !self.has_block(block.get_pre_block_hash().as_bytes()) {
+            return Err(BlockValidationError::UnknownParent(
+                block.get_pre_block_hash(),
+            ));
+        }
+        self.validate_transactions(block)
+    }
+
+    fn validate_transactions(&self, block: &Block) -> Result<(), BlockValidationError> {
+        let coinbase_count = block
+            .get_transactions()
+            .iter()
+            .filter(|tx| tx.is_coinbase())
+            .count();
+        if coinbase_count != 1 {
+            return Err(BlockValidationError::InvalidCoinbaseCount(coinbase_count));
+        }
+
+        let prev_txs = self.find_block_inputs(block)?;
+        let mut fees: i64 = 0;
+        let mut coinbase_value: i64 = 0;
+        for tx in block.get_transactions() {
+            if tx.is_coinbase() {
+                coinbase_value = tx.get_vout().iter().map(|out| out.get_value() as i64).sum();
+                continue;
             }
-            Ok(())
-        });
+            fees += check_spend(tx, &prev_txs)?;
+        }
+
+        let max_coinbase_value = SUBSIDY as i64 + fees;
+        if coinbase_value > max_coinbase_value {
+            return Err(BlockValidationError::CoinbaseValueTooHigh {
+                max: max_coinbase_value,
+                found: coinbase_value,
+            });
+        }
+        Ok(())
     }
 
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TXOutput>> {
-        let mut utxo: HashMap<String, Vec<TXOutput>> = HashMap::new();
-        let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
-        let iterator = self.iterator();
+    /// Checks a transaction offered for the memory pool like `validate_transactions`
+    /// checks one in a block, against the best chain. A coinbase is only valid in a
+    /// block.
+    pub fn validate_transaction(&self, tx: &Transaction) -> Result<(), BlockValidationError> {
+        if tx.is_coinbase() {
+            return Err(BlockValidationError::UnexpectedCoinbase(
+                HEXLOWER.encode(tx.get_id()),
+            ));
+        }
+        let mut prev_txs = HashMap::new();
+        for vin in tx.get_vin() {
+            let prev_txid_hex = HEXLOWER.encode(vin.get_txid());
+            let prev_tx = self.find_transaction(vin.get_txid()).ok_or_else(|| {
+                BlockValidationError::MissingInput {
+                    txid: prev_txid_hex.clone(),
+                    vout: vin.get_vout(),
+                }
+            })?;
+            prev_txs.insert(prev_txid_hex, prev_tx);
+        }
+        check_spend(tx, &prev_txs).map(|_| ())
+    }
 
-        for block in iterator {
-            'outer: for tx in block.get_transactions() {
-                let txid_hex = HEXLOWER.encode(tx.get_id());
-                for (idx, out) in tx.get_vout().iter().enumerate() {
-                    if let Some(outs) = spent_txos.get(txid_hex.as_str()) {
-                        for spent_out in outs {
-                            if idx == *spent_out {
-                                continue 'outer;
-                            }
+    /// Collects the transactions the block's inputs spend, looking at earlier
+    /// transactions in the block and then at the block's own branch. Fails when an
+    /// input is unknown or its output was already spent, either within the block or by
+    /// an ancestor.
+    fn find_block_inputs(
+        &self,
+        block: &Block,
+    ) -> Result<HashMap<String, Transaction>, BlockValidationError> {
+        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+        let mut in_block: HashMap<String, Transaction> = HashMap::new();
+        let mut spent: HashSet<(String, usize)> = HashSet::new();
+        let mut pending: HashSet<String> = HashSet::new();
+        for tx in block.get_transactions() {
+            let txid_hex = HEXLOWER.encode(tx.get_id());
+            if in_block.contains_key(txid_hex.as_str()) {
+                return Err(BlockValidationError::DuplicateTransaction(txid_hex));
+            }
+            if !tx.is_coinbase() {
+                for vin in tx.get_vin() {
+                    let prev_txid_hex = HEXLOWER.encode(vin.get_txid());
+                    if !spent.insert((prev_txid_hex.clone(), vin.get_vout())) {
+                        return Err(BlockValidationError::DoubleSpend {
+                            txid: prev_txid_hex,
+                            vout: vin.get_vout(),
+                        });
+                    }
+                    match in_block.get(prev_txid_hex.as_str()) {
+                        Some(prev_tx) => {
+                            prev_txs.insert(prev_txid_hex, prev_tx.clone());
+                        }
+                        None => {
+                            pending.insert(prev_txid_hex);
                         }
                     }
-                    if utxo.contains_key(txid_hex.as_str()) {
-                        utxo.get_mut(txid_hex.as_str()).unwrap().push(out.clone());
-                    } else {
-                        utxo.insert(txid_hex.clone(), vec![out.clone()]);
+                }
+            }
+            in_block.insert(txid_hex, tx.clone());
+        }
+
+        // Walk back until every referenced transaction is found. Any spend of the
+        // same outputs has to sit between the creating block and this one.
+        let mut current_hash = block.get_pre_block_hash();
+        while !pending.is_empty() {
+            let ancestor = match self.get_block(current_hash.as_bytes()) {
+                Some(ancestor) => ancestor,
+                None => break,
+            };
+            for tx in ancestor.get_transactions() {
+                if !tx.is_coinbase() {
+                    for vin in tx.get_vin() {
+                        let outpoint = (HEXLOWER.encode(vin.get_txid()), vin.get_vout());
+                        if spent.contains(&outpoint) {
+                            return Err(BlockValidationError::DoubleSpend {
+                                txid: outpoint.0,
+                                vout: outpoint.1,
+                            });
+                        }
                     }
                 }
+                let txid_hex = HEXLOWER.encode(tx.get_id());
+                if pending.remove(txid_hex.as_str()) {
+                    prev_txs.insert(txid_hex, tx.clone());
+                }
+            }
+            if ancestor.get_height() == 0 {
+                break;
+            }
+            current_hash = ancestor.get_pre_block_hash();
+        }
+
+        for (txid_hex, vout) in spent {
+            let exists = prev_txs
+                .get(txid_hex.as_str())
+                .is_some_and(|prev_tx| vout < prev_tx.get_vout().len());
+            if !exists {
+                return Err(BlockValidationError::MissingInput {
+                    txid: txid_hex,
+                    vout,
+                });
+            }
+        }
+        Ok(prev_txs)
+    }
+
+    /// Stores a block and makes it the tip when its branch has more cumulative work
+    /// than the current best chain. If that branch does not extend the old tip, the
+    /// blocks back to the common ancestor are reverted from the chain state before the
+    /// new branch is connected. The block must already have passed `validate_block`.
+    /// Fails, keeping the old tip, if the new branch doesn't apply to the UTXO set.
+    pub fn add_block(&self, block: &Block) -> Result<ChainUpdate, BlockValidationError> {
+        let _guard = self.update_lock.lock().unwrap();
+        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
+        if self.has_block(block.get_hash().as_bytes()) {
+            return Ok(ChainUpdate::default());
+        }
+        let parent_work = self.get_chain_work(block.get_pre_block_hash().as_str());
+        let chain_work = parent_work + proof_of_work::block_work(block.get_bits());
+        Self::update_blocks_tree(&self.db, block);
+        Self::update_chain_work_tree(&self.db, block.get_hash(), &chain_work);
+
+        let tip_work = self.get_chain_work(self.get_tip_hash().as_str());
+        if chain_work <= tip_work {
+            return Ok(ChainUpdate::default());
+        }
+
+        let update = self.find_fork(block);
+        if !update.disconnected.is_empty() {
+            info!(
+                "Reorganizing: disconnecting {} blocks, connecting {} blocks",
+                update.disconnected.len(),
+                update.connected.len()
+            );
+        }
+        // The UTXO set moves first, so a branch that doesn't apply leaves the old tip
+        // in place. Blocks connected before undo data existed can only be rolled back
+        // by replaying the new best chain once the tip has moved.
+        let needs_reindex = !self.apply_utxo_changes(&update)?;
+
+        let _ = block_tree
+            .insert(TIP_BLOCK_HASH_KEY, block.get_hash())
+            .unwrap();
+        self.set_tip_hash(block.get_hash());
+        for disconnected in &update.disconnected {
+            Self::remove_from_tx_index_tree(&self.db, disconnected);
+            Self::remove_from_height_index_tree(&self.db, disconnected);
+        }
+        for connected in &update.connected {
+            Self::update_tx_index_tree(&self.db, connected);
+            Self::update_height_index_tree(&self.db, connected);
+        }
+        if needs_reindex {
+            UTXOSet::new(self.clone()).reindex();
+        }
+        Ok(update)
+    }
+
+    /// Reverts the disconnected blocks and applies the connected ones to the UTXO
+    /// set. If a connected block doesn't apply, everything is put back and the error
+    /// returned. Returns false if a block has no undo data, so the set has to be
+    /// rebuilt with `reindex`.
+    fn apply_utxo_changes(&self, update: &ChainUpdate) -> Result<bool, BlockValidationError> {
+        let utxo_set = UTXOSet::new(self.clone());
+        let mut reverted = vec![];
+        for disconnected in &update.disconnected {
+            if !utxo_set.revert(disconnected) {
+                return Ok(false);
+            }
+            reverted.push(disconnected);
+        }
+        let mut applied: Vec<&Block> = vec![];
+        for connected in &update.connected {
+            if let Err(e) = utxo_set.update(connected) {
+                for block in applied.iter().rev() {
+                    utxo_set.revert(block);
+                }
+                for block in reverted.iter().rev() {
+                    utxo_set
+                        .update(block)
+                        .expect("a reverted block applies again");
+                }
+                return Err(e);
+            }
+            applied.push(connected);
+        }
+        Ok(true)
+    }
+
+    /// Walks the current tip and `new_tip` back to their common ancestor
+    fn find_fork(&self, new_tip: &Block) -> ChainUpdate {
+        let parent_of = |block: &Block| {
+            self.get_block(block.get_pre_block_hash().as_bytes())
+                .expect("The parent chain is complete")
+        };
+        let mut old = self
+            .get_block(self.get_tip_hash().as_bytes())
+            .expect("The tip hash is valid");
+        let mut new = new_tip.clone();
+        let mut disconnected = vec![];
+        let mut connected = vec![];
+        while new.get_height() > old.get_height() {
+            let parent = parent_of(&new);
+            connected.push(new);
+            new = parent;
+        }
+        while old.get_height() > new.get_height() {
+            let parent = parent_of(&old);
+            disconnected.push(old);
+            old = parent;
+        }
+        while old.get_hash() != new.get_hash() {
+            let old_parent = parent_of(&old);
+            let new_parent = parent_of(&new);
+            disconnected.push(old);
+            connected.push(new);
+            old = old_parent;
+            new = new_parent;
+        }
+        connected.reverse();
+        ChainUpdate {
+            disconnected,
+            connected,
+        }
+    }
+
+    /// Replays the best chain into a fresh UTXO map keyed by `utxo_set::outpoint_key`
+    pub fn find_utxo(&self) -> HashMap<Vec<u8>, UTXOEntry> {
+        let mut utxo: HashMap<Vec<u8>, UTXOEntry> = HashMap::new();
+        let mut spent_txos: HashSet<Vec<u8>> = HashSet::new();
+        let iterator = self.iterator();
+
+        for block in iterator {
+            // Spends inside a block come after the outputs they spend, so record them
+            // before looking at the block's outputs
+            for tx in block.get_transactions() {
                 if tx.is_coinbase() {
                     continue;
                 }
-
                 for txin in tx.get_vin() {
-                    let txid_hex = HEXLOWER.encode(txin.get_txid());
-                    if spent_txos.contains_key(txid_hex.as_str()) {
-                        spent_txos
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(txin.get_vout());
-                    } else {
-                        spent_txos.insert(txid_hex, vec![txin.get_vout()]);
+                    spent_txos.insert(utxo_set::outpoint_key(txin.get_txid(), txin.get_vout()));
+                }
+            }
+            for tx in block.get_transactions() {
+                for (idx, out) in tx.get_vout().iter().enumerate() {
+                    let key = utxo_set::outpoint_key(tx.get_id(), idx);
+                    if spent_txos.contains(&key) {
+                        continue;
                     }
+                    let entry = UTXOEntry::new(out.clone(), block.get_height(), tx.is_coinbase());
+                    utxo.insert(key, entry);
                 }
             }
         }
@@ -204,6 +740,35 @@ impl Blockchain {
     }
 }
 
+/// Joins a stored header with its body
+fn load_block(db: &Db, block_hash: &[u8]) -> Option<Block> {
+    let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
+    let bodies_tree = db.open_tree(BLOCK_BODIES_TREE).unwrap();
+    let header_bytes = blocks_tree.get(block_hash).unwrap()?;
+    let body_bytes = bodies_tree.get(block_hash).unwrap()?;
+    let header = BlockHeader::deserialize(header_bytes.as_ref()).unwrap();
+    let transactions = bincode::deserialize(body_bytes.as_ref()).expect("unable to deserialize");
+    Some(Block::from_parts(header, transactions))
+}
+
+/// Blocks that left the best chain, newest first, and blocks that joined it, oldest
+/// first, as the result of `add_block`
+#[derive(Default)]
+pub struct ChainUpdate {
+    disconnected: Vec<Block>,
+    connected: Vec<Block>,
+}
+
+impl ChainUpdate {
+    pub fn get_disconnected(&self) -> &[Block] {
+        self.disconnected.as_slice()
+    }
+
+    pub fn get_connected(&self) -> &[Block] {
+        self.connected.as_slice()
+    }
+}
+
 pub struct BlockchainIterator {
     db: Db,
     current_hash: String,
@@ -218,10 +783,7 @@ impl BlockchainIterator {
     }
 
     pub fn next(&mut self) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let data = block_tree.get(self.current_hash.clone()).unwrap()?;
-
-        let block = Block::deserialize(data.to_vec().as_slice());
+        let block = load_block(&self.db, self.current_hash.as_bytes())?;
         self.current_hash = block.get_pre_block_hash();
         Some(block)
     }
@@ -234,3 +796,524 @@ impl Iterator for BlockchainIterator {
         self.next()
     }
 }
+
+pub struct BlockchainForwardIterator {
+    blockchain: Blockchain,
+    next_height: usize,
+}
+
+impl BlockchainForwardIterator {
+    pub fn new(blockchain: Blockchain) -> BlockchainForwardIterator {
+        Self {
+            blockchain,
+            next_height: 0,
+        }
+    }
+}
+
+impl Iterator for BlockchainForwardIterator {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = self.blockchain.get_block_by_height(self.next_height)?;
+        self.next_height += 1;
+        Some(block)
+    }
+}
+
+/// Checks the signatures and amounts of a non-coinbase `tx` whose inputs are all in
+/// `prev_txs`, returning the fee it pays
+fn check_spend(
+    tx: &Transaction,
+    prev_txs: &HashMap<String, Transaction>,
+) -> Result<i64, BlockValidationError> {
+    let txid_hex = HEXLOWER.encode(tx.get_id());
+    if tx.get_vin().is_empty() {
+        return Err(BlockValidationError::NoInputs(txid_hex));
+    }
+    if !tx.verify_with_prev_txs(prev_txs) {
+        return Err(BlockValidationError::InvalidSignature(txid_hex));
+    }
+    let mut input_value: i64 = 0;
+    for vin in tx.get_vin() {
+        let prev_tx = &prev_txs[HEXLOWER.encode(vin.get_txid()).as_str()];
+        input_value += prev_tx.get_vout()[vin.get_vout()].get_value() as i64;
+    }
+    let output_value: i64 = tx.get_vout().iter().map(|out| out.get_value() as i64).sum();
+    if tx.get_vout().iter().any(|out| out.get_value() < 0) || output_value > input_value {
+        return Err(BlockValidationError::OutputsExceedInputs(txid_hex));
+    }
+    Ok(input_value - output_value)
+}
+
+/// Why a block received from a peer, or a transaction offered for the memory pool,
+/// was refused
+#[derive(Debug)]
+pub enum BlockValidationError {
+    AlreadyKnown,
+    InvalidProofOfWork,
+    UnknownParent(String),
+    InvalidHeight { expected: usize, found: usize },
+    InvalidBits { expected: u32, found: u32 },
+    TimestampTooOld { median_time_past: i64, found: i64 },
+    TimestampTooNew { max: i64, found: i64 },
+    InvalidMerkleRoot,
+    InvalidCoinbaseCount(usize),
+    UnexpectedCoinbase(String),
+    DuplicateTransaction(String),
+    MissingInput { txid: String, vout: usize },
+    DoubleSpend { txid: String, vout: usize },
+    NoInputs(String),
+    InvalidSignature(String),
+    OutputsExceedInputs(String),
+    CoinbaseValueTooHigh { max: i64, found: i64 },
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockValidationError::AlreadyKnown => write!(f, "block is already known"),
+            BlockValidationError::InvalidProofOfWork => write!(f, "invalid proof of work"),
+            BlockValidationError::UnknownParent(hash) => write!(f, "unknown parent {}", hash),
+            BlockValidationError::InvalidHeight { expected, found } => {
+                write!(
+                    f,
+                    "height {} does not follow parent, expected {}",
+                    found, expected
+                )
+            }
+            BlockValidationError::InvalidBits { expected, found } => {
+                write!(
+                    f,
+                    "bits {:#010x} do not match expected {:#010x}",
+                    found, expected
+                )
+            }
+            BlockValidationError::TimestampTooOld {
+                median_time_past,
+                found,
+            } => write!(
+                f,
+                "timestamp {} is not after median time past {}",
+                found, median_time_past
+            ),
+            BlockValidationError::TimestampTooNew { max, found } => {
+                write!(f, "timestamp {} is later than the allowed {}", found, max)
+            }
+            BlockValidationError::InvalidMerkleRoot => {
+                write!(f, "merkle root does not match the transactions")
+            }
+            BlockValidationError::InvalidCoinbaseCount(count) => {
+                write!(f, "expected one coinbase transaction, found {}", count)
+            }
+            BlockValidationError::UnexpectedCoinbase(txid) => {
+                write!(f, "transaction {} is a coinbase outside a block", txid)
+            }
+            BlockValidationError::DuplicateTransaction(txid) => {
+                write!(f, "transaction {} appears more than once", txid)
+            }
+            BlockValidationError::MissingInput { txid, vout } => {
+                write!(f, "input {}:{} does not exist", txid, vout)
+            }
+            BlockValidationError::DoubleSpend { txid, vout } => {
+                write!(f, "output {}:{} is already spent", txid, vout)
+            }
+            BlockValidationError::NoInputs(txid) => {
+                write!(f, "transaction {} has no inputs", txid)
+            }
+            BlockValidationError::InvalidSignature(txid) => {
+                write!(f, "transaction {} has an invalid signature", txid)
+            }
+            BlockValidationError::OutputsExceedInputs(txid) => {
+                write!(f, "transaction {} spends more than its inputs", txid)
+            }
+            BlockValidationError::CoinbaseValueTooHigh { max, found } => {
+                write!(f, "coinbase pays {}, more than the allowed {}", found, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockValidationError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        transaction::{TXInput, TXOutput},
+        wallet::{hash_pub_key, Wallet},
+    };
+
+    fn mine_child(parent: &Block, transactions: &[Transaction]) -> Block {
+        mine_child_at(
+            parent,
+            transactions,
+            crate::current_timestamp().max(parent.get_timestamp() + 1),
+        )
+    }
+
+    fn mine_child_at(parent: &Block, transactions: &[Transaction], timestamp: i64) -> Block {
+        Block::new_block(
+            parent.get_hash().to_string(),
+            transactions,
+            parent.get_height() + 1,
+            parent.get_bits(),
+            timestamp,
+        )
+    }
+
+    #[test]
+    fn block_that_does_not_apply_keeps_the_tip() {
+        let alice = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+        let genesis = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        let pay = |to: &Wallet| {
+            let outputs = vec![TXOutput::new(SUBSIDY, &to.get_address())];
+            Transaction::new_test_spend(genesis_coinbase, 0, &alice, outputs)
+        };
+
+        let double_spend = mine_child(
+            &genesis,
+            &[
+                pay(&Wallet::new()),
+                pay(&Wallet::new()),
+                Transaction::new_coinbase_tx(&alice.get_address()),
+            ],
+        );
+        assert!(matches!(
+            blockchain.add_block(&double_spend),
+            Err(BlockValidationError::DoubleSpend { .. })
+        ));
+        assert_eq!(blockchain.get_tip_hash(), genesis.get_hash());
+        assert!(utxo_set.get_utxo(genesis_coinbase.get_id(), 0).is_some());
+
+        // The chain still accepts blocks afterwards
+        let valid = mine_child(
+            &genesis,
+            &[
+                pay(&Wallet::new()),
+                Transaction::new_coinbase_tx(&alice.get_address()),
+            ],
+        );
+        assert!(blockchain.add_block(&valid).is_ok());
+        assert_eq!(blockchain.get_tip_hash(), valid.get_hash());
+        assert!(utxo_set.get_utxo(genesis_coinbase.get_id(), 0).is_none());
+    }
+
+    #[test]
+    fn mined_double_spend_is_rejected_before_it_is_added() {
+        let alice = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        UTXOSet::new(blockchain.clone()).reindex();
+        let genesis_hash = blockchain.get_tip_hash();
+        let genesis = blockchain.get_block(genesis_hash.as_bytes()).unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        let pay = |to: &Wallet| {
+            let outputs = vec![TXOutput::new(SUBSIDY, &to.get_address())];
+            Transaction::new_test_spend(genesis_coinbase, 0, &alice, outputs)
+        };
+
+        let result = blockchain.mine_block(&[
+            pay(&Wallet::new()),
+            pay(&Wallet::new()),
+            Transaction::new_coinbase_tx(&alice.get_address()),
+        ]);
+        assert!(matches!(
+            result,
+            Err(BlockValidationError::DoubleSpend { .. })
+        ));
+        assert_eq!(blockchain.get_tip_hash(), genesis_hash);
+    }
+
+    #[test]
+    fn hashes_after_start_at_the_fork_and_end_at_the_stop_hash() {
+        let alice = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        UTXOSet::new(blockchain.clone()).reindex();
+        let genesis = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap();
+        let coinbase = || [Transaction::new_coinbase_tx(&alice.get_address())];
+        let best: Vec<Vec<u8>> = (0..4)
+            .map(|_| blockchain.mine_block(&coinbase()).unwrap().get_hash_bytes())
+            .collect();
+        // A shorter branch off genesis, stored but not on the best chain
+        let side = mine_child(&genesis, &coinbase());
+        blockchain.add_block(&side).unwrap();
+        let side_locator = vec![side.get_hash_bytes(), genesis.get_hash_bytes()];
+
+        assert_eq!(
+            blockchain.get_block_hashes_after(&side_locator, &[], 10),
+            best
+        );
+        assert_eq!(
+            blockchain.get_block_hashes_after(&side_locator, &best[1], 10),
+            best[..2]
+        );
+        assert_eq!(
+            blockchain.get_block_hashes_after(&side_locator, &[], 3),
+            best[..3]
+        );
+        // A stop hash that is not reached within `max` doesn't shorten the list
+        assert_eq!(
+            blockchain.get_block_hashes_after(&side_locator, &best[3], 2),
+            best[..2]
+        );
+        let behind = vec![best[1].clone(), genesis.get_hash_bytes()];
+        assert_eq!(
+            blockchain.get_block_hashes_after(&behind, &[], 10),
+            best[2..]
+        );
+        assert!(blockchain
+            .get_block_hashes_after(&blockchain.get_block_locator(), &[], 10)
+            .is_empty());
+        // Nothing in common starts from genesis
+        let unknown = vec![vec![0; 32]];
+        let from_genesis = blockchain.get_block_hashes_after(&unknown, &best[0], 10);
+        assert_eq!(
+            from_genesis,
+            vec![genesis.get_hash_bytes(), best[0].clone()]
+        );
+    }
+
+    #[test]
+    fn pool_transactions_get_the_block_checks() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        let genesis = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        let pay = |value: i32| {
+            let outputs = vec![TXOutput::new(value, &bob.get_address())];
+            Transaction::new_test_spend(genesis_coinbase, 0, &alice, outputs)
+        };
+
+        assert!(blockchain.validate_transaction(&pay(SUBSIDY)).is_ok());
+        assert!(matches!(
+            blockchain.validate_transaction(&pay(SUBSIDY + 1)),
+            Err(BlockValidationError::OutputsExceedInputs(_))
+        ));
+        assert!(matches!(
+            blockchain.validate_transaction(&pay(-1)),
+            Err(BlockValidationError::OutputsExceedInputs(_))
+        ));
+        let no_inputs = Transaction::new_test_unsigned(
+            vec![],
+            vec![TXOutput::new(SUBSIDY, &bob.get_address())],
+        );
+        assert!(matches!(
+            blockchain.validate_transaction(&no_inputs),
+            Err(BlockValidationError::NoInputs(_))
+        ));
+        assert!(matches!(
+            blockchain.validate_transaction(&Transaction::new_coinbase_tx(&bob.get_address())),
+            Err(BlockValidationError::UnexpectedCoinbase(_))
+        ));
+        // Two inputs, so the transaction isn't coinbase-shaped
+        let unknown = vec![TXInput::new(&[7; 32], 0), TXInput::new(&[7; 32], 1)];
+        assert!(matches!(
+            blockchain.validate_transaction(&Transaction::new_test_unsigned(unknown, vec![])),
+            Err(BlockValidationError::MissingInput { .. })
+        ));
+    }
+
+    #[test]
+    fn median_time_past_covers_the_last_eleven_headers() {
+        let alice = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        let mut parent = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap();
+        let start = parent.get_timestamp();
+        assert_eq!(blockchain.get_median_time_past(parent.get_header()), start);
+
+        let mut headers = vec![];
+        for i in 1..=12 {
+            let child = mine_child_at(&parent, &[], start + i * 1000);
+            blockchain.add_header(child.get_header());
+            headers.push(child.get_header().clone());
+            parent = child;
+        }
+        // Genesis plus two children: the middle one
+        assert_eq!(blockchain.get_median_time_past(&headers[1]), start + 1000);
+        // Heights 2 through 12, so genesis and height 1 have dropped out
+        assert_eq!(blockchain.get_median_time_past(&headers[11]), start + 7000);
+    }
+
+    #[test]
+    fn header_timestamps_must_follow_the_median_and_not_run_ahead() {
+        let alice = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        let genesis = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap();
+        let median_time_past = blockchain.get_median_time_past(genesis.get_header());
+
+        let too_old = mine_child_at(&genesis, &[], median_time_past);
+        assert!(matches!(
+            blockchain.validate_header(too_old.get_header()),
+            Err(BlockValidationError::TimestampTooOld { .. })
+        ));
+
+        let too_new = mine_child_at(
+            &genesis,
+            &[],
+            crate::current_timestamp() + MAX_FUTURE_BLOCK_TIME + 60_000,
+        );
+        assert!(matches!(
+            blockchain.validate_header(too_new.get_header()),
+            Err(BlockValidationError::TimestampTooNew { .. })
+        ));
+
+        let next = mine_child_at(&genesis, &[], median_time_past + 1);
+        assert!(blockchain.validate_header(next.get_header()).is_ok());
+    }
+
+    #[test]
+    fn mined_block_stays_ahead_of_a_chain_in_the_future() {
+        let alice = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        UTXOSet::new(blockchain.clone()).reindex();
+        let mut parent = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap();
+        // Peers whose clocks run an hour ahead of ours mined the last few blocks
+        let ahead = crate::current_timestamp() + 60 * 60 * 1000;
+        for i in 0..3 {
+            let coinbase = Transaction::new_coinbase_tx(&alice.get_address());
+            let child = mine_child_at(&parent, &[coinbase], ahead + i);
+            blockchain.add_block(&child).unwrap();
+            parent = child;
+        }
+        let median_time_past = blockchain.get_median_time_past(parent.get_header());
+        assert!(median_time_past > crate::current_timestamp());
+
+        let block = blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(&alice.get_address())])
+            .unwrap();
+        assert!(block.get_timestamp() > median_time_past);
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+    }
+
+    #[test]
+    fn longer_branch_replaces_the_tip_and_its_transactions() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let carol = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+        let genesis = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        let pay = |to: &Wallet| {
+            let outputs = vec![TXOutput::new(SUBSIDY, &to.get_address())];
+            Transaction::new_test_spend(genesis_coinbase, 0, &alice, outputs)
+        };
+
+        let pay_bob = pay(&bob);
+        let a1 = mine_child(
+            &genesis,
+            &[
+                pay_bob.clone(),
+                Transaction::new_coinbase_tx(&alice.get_address()),
+            ],
+        );
+        blockchain.add_block(&a1).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), a1.get_hash());
+
+        // The competing branch spends the same coin to carol instead
+        let pay_carol = pay(&carol);
+        let b1 = mine_child(
+            &genesis,
+            &[
+                pay_carol.clone(),
+                Transaction::new_coinbase_tx(&alice.get_address()),
+            ],
+        );
+        let update = blockchain.add_block(&b1).unwrap();
+        assert!(update.get_connected().is_empty());
+        assert_eq!(blockchain.get_tip_hash(), a1.get_hash());
+        let b2 = mine_child(&b1, &[Transaction::new_coinbase_tx(&alice.get_address())]);
+        let update = blockchain.add_block(&b2).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+        let hashes = |blocks: &[Block]| -> Vec<String> {
+            blocks.iter().map(|b| b.get_hash().to_string()).collect()
+        };
+        assert_eq!(hashes(update.get_disconnected()), vec![a1.get_hash()]);
+        assert_eq!(
+            hashes(update.get_connected()),
+            vec![b1.get_hash(), b2.get_hash()]
+        );
+
+        // UTXO set: A1's outputs are gone, B1's and B2's are in
+        assert!(utxo_set.get_utxo(genesis_coinbase.get_id(), 0).is_none());
+        for tx in a1.get_transactions() {
+            assert!(utxo_set.get_utxo(tx.get_id(), 0).is_none());
+        }
+        for tx in b1.get_transactions().iter().chain(b2.get_transactions()) {
+            assert!(utxo_set.get_utxo(tx.get_id(), 0).is_some());
+        }
+        assert!(utxo_set
+            .find_utxo(&hash_pub_key(bob.get_public_key()))
+            .is_empty());
+        assert_eq!(
+            utxo_set
+                .find_utxo(&hash_pub_key(carol.get_public_key()))
+                .len(),
+            1
+        );
+
+        // Only transactions on the best chain can be looked up
+        for tx in a1.get_transactions() {
+            assert!(blockchain.find_transaction(tx.get_id()).is_none());
+        }
+        assert!(blockchain.find_transaction(pay_carol.get_id()).is_some());
+
+        // The height index and the forward walk follow the new branch
+        assert_eq!(
+            blockchain.get_block_by_height(1).unwrap().get_hash(),
+            b1.get_hash()
+        );
+        assert_eq!(
+            blockchain.get_block_by_height(2).unwrap().get_hash(),
+            b2.get_hash()
+        );
+        assert!(blockchain.get_block_by_height(3).is_none());
+        assert_eq!(
+            hashes(&blockchain.forward_iterator().collect::<Vec<_>>()),
+            vec![genesis.get_hash(), b1.get_hash(), b2.get_hash()]
+        );
+    }
+
+    #[test]
+    fn orphan_body_must_match_its_header() {
+        let alice = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        let genesis = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap();
+        let coinbase = || Transaction::new_coinbase_tx(&alice.get_address());
+        let parent = mine_child(&genesis, &[coinbase()]);
+        blockchain.add_header(parent.get_header());
+        let orphan = mine_child(&parent, &[coinbase()]);
+        assert!(matches!(
+            blockchain.validate_block(&orphan),
+            Err(BlockValidationError::UnknownParent(_))
+        ));
+
+        let swapped_body = Block::from_parts(orphan.get_header().clone(), vec![coinbase()]);
+        assert!(matches!(
+            blockchain.validate_block(&swapped_body),
+            Err(BlockValidationError::InvalidMerkleRoot)
+        ));
+    }
+}
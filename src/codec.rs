@@ -0,0 +1,308 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use bincode::Options;
+
+use crate::{server::Package, utils::sha256_digest};
+
+/// Marks the start of every message on the wire
+pub const NETWORK_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+/// Largest payload a peer may send in one message
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+const COMMAND_LEN: usize = 12;
+const CHECKSUM_LEN: usize = 4;
+/// magic || command || payload length (u32 BE) || checksum
+const HEADER_LEN: usize = NETWORK_MAGIC.len() + COMMAND_LEN + 4 + CHECKSUM_LEN;
+
+/// Same encoding as `bincode::serialize`, but bounded and without trailing bytes
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_MESSAGE_SIZE as u64)
+}
+
+/// Name of the message type, sent in the header next to the payload
+fn command(pkg: &Package) -> &'static str {
+    match pkg {
+        Package::Addr { .. } => "addr",
+        Package::Block { .. } => "block",
+        Package::GetAddr { .. } => "getaddr",
+        Package::GetData { .. } => "getdata",
+        Package::GetHeaders { .. } => "getheaders",
+        Package::Headers { .. } => "headers",
+        Package::Inv { .. } => "inv",
+        Package::Ping { .. } => "ping",
+        Package::Pong { .. } => "pong",
+        Package::Tx { .. } => "tx",
+        Package::Version { .. } => "version",
+    }
+}
+
+/// First four bytes of the double SHA-256 of the payload
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = sha256_digest(sha256_digest(payload).as_slice());
+    hash[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+/// Frames a package as a single message
+pub fn encode(pkg: &Package) -> Result<Vec<u8>, CodecError> {
+    let payload = bincode_options()
+        .serialize(pkg)
+        .map_err(CodecError::Malformed)?;
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(CodecError::MessageTooLarge(payload.len()));
+    }
+    let mut command_bytes = [0; COMMAND_LEN];
+    let name = command(pkg).as_bytes();
+    command_bytes[..name.len()].copy_from_slice(name);
+
+    let mut message = Vec::with_capacity(HEADER_LEN + payload.len());
+    message.extend(NETWORK_MAGIC);
+    message.extend(command_bytes);
+    message.extend((payload.len() as u32).to_be_bytes());
+    message.extend(checksum(payload.as_slice()));
+    message.extend(payload);
+    Ok(message)
+}
+
+pub fn write_message<W: Write>(writer: &mut W, pkg: &Package) -> Result<(), CodecError> {
+    let message = encode(pkg)?;
+    writer.write_all(message.as_slice())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next message. Returns `None` when the stream ends cleanly between
+/// messages. The header is checked before the payload is read, so an oversized
+/// message is refused without buffering it.
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Option<Package>, CodecError> {
+    let mut header = [0; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(CodecError::Truncated),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(CodecError::Io(e)),
+        }
+    }
+
+    let (magic, rest) = header.split_at(NETWORK_MAGIC.len());
+    let (command_bytes, rest) = rest.split_at(COMMAND_LEN);
+    let (length_bytes, expected_checksum) = rest.split_at(4);
+    if magic != NETWORK_MAGIC {
+        return Err(CodecError::BadMagic);
+    }
+    let length = u32::from_be_bytes(length_bytes.try_into().unwrap()) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(CodecError::MessageTooLarge(length));
+    }
+
+    let mut payload = vec![0; length];
+    reader
+        .read_exact(&mut payload)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => CodecError::Truncated,
+            _ => CodecError::Io(e),
+        })?;
+    if checksum(payload.as_slice()) != expected_checksum {
+        return Err(CodecError::BadChecksum);
+    }
+    let pkg: Package = bincode_options()
+        .deserialize(payload.as_slice())
+        .map_err(CodecError::Malformed)?;
+
+    let name = command(&pkg).as_bytes();
+    let mut expected_command = [0; COMMAND_LEN];
+    expected_command[..name.len()].copy_from_slice(name);
+    if command_bytes != expected_command {
+        return Err(CodecError::CommandMismatch);
+    }
+    Ok(Some(pkg))
+}
+
+/// Why a message could not be read or written
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    BadMagic,
+    Truncated,
+    MessageTooLarge(usize),
+    BadChecksum,
+    CommandMismatch,
+    Malformed(bincode::Error),
+}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "i/o error: {}", e),
+            CodecError::BadMagic => write!(f, "bad network magic"),
+            CodecError::Truncated => write!(f, "message is truncated"),
+            CodecError::MessageTooLarge(len) => write!(
+                f,
+                "payload of {} bytes exceeds the limit of {}",
+                len, MAX_MESSAGE_SIZE
+            ),
+            CodecError::BadChecksum => write!(f, "payload checksum does not match"),
+            CodecError::CommandMismatch => write!(f, "command does not match the payload"),
+            CodecError::Malformed(e) => write!(f, "malformed payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::OpType;
+
+    /// Parses exactly one framed message
+    fn decode(bytes: &[u8]) -> Result<Package, CodecError> {
+        let mut reader = bytes;
+        let pkg = read_message(&mut reader)?.ok_or(CodecError::Truncated)?;
+        if !reader.is_empty() {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "trailing bytes");
+            return Err(CodecError::Io(e));
+        }
+        Ok(pkg)
+    }
+
+    /// xorshift64, so the fuzz cases are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    fn sample_packages() -> Vec<Package> {
+        vec![
+            Package::Version {
+                addr_from: String::from("127.0.0.1:2001"),
+                version: 1,
+                best_height: 42,
+            },
+            Package::Inv {
+                addr_from: String::from("127.0.0.1:2002"),
+                op_type: OpType::Block,
+                items: vec![vec![1; 64], vec![2; 64]],
+            },
+            Package::Block {
+                addr_from: String::from("127.0.0.1:2003"),
+                block: vec![7; 1000],
+            },
+            Package::GetHeaders {
+                addr_from: String::from("127.0.0.1:2004"),
+                locator: vec![vec![3; 64]],
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_sample() {
+        for pkg in sample_packages() {
+            let message = encode(&pkg).unwrap();
+            let decoded = decode(message.as_slice()).unwrap();
+            assert_eq!(encode(&decoded).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn reads_consecutive_messages_from_a_stream() {
+        let mut stream = vec![];
+        for pkg in sample_packages() {
+            write_message(&mut stream, &pkg).unwrap();
+        }
+        let mut reader = stream.as_slice();
+        let mut count = 0;
+        while let Some(pkg) = read_message(&mut reader).unwrap() {
+            assert_eq!(command(&pkg), command(&sample_packages()[count]));
+            count += 1;
+        }
+        assert_eq!(count, sample_packages().len());
+    }
+
+    #[test]
+    fn every_truncation_is_rejected() {
+        for pkg in sample_packages() {
+            let message = encode(&pkg).unwrap();
+            for len in 0..message.len() {
+                assert!(decode(&message[..len]).is_err(), "accepted {} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn every_single_bit_flip_is_rejected() {
+        let message = encode(&sample_packages()[1]).unwrap();
+        for pos in 0..message.len() {
+            for bit in 0..8 {
+                let mut corrupted = message.clone();
+                corrupted[pos] ^= 1 << bit;
+                assert!(
+                    decode(corrupted.as_slice()).is_err(),
+                    "accepted flip of bit {} at {}",
+                    bit,
+                    pos
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_length_is_rejected_before_reading_the_payload() {
+        let mut message = encode(&sample_packages()[0]).unwrap();
+        let length = (MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes();
+        message[NETWORK_MAGIC.len() + COMMAND_LEN..][..4].copy_from_slice(&length);
+        assert!(matches!(
+            decode(message.as_slice()),
+            Err(CodecError::MessageTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn random_input_never_panics() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let len = (rng.next() % 256) as usize;
+            let _ = decode(rng.bytes(len).as_slice());
+        }
+    }
+
+    #[test]
+    fn random_payloads_behind_a_valid_header_never_panic() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..2000 {
+            let len = (rng.next() % 512) as usize;
+            let payload = rng.bytes(len);
+            let mut message = NETWORK_MAGIC.to_vec();
+            let mut command_bytes = [0; COMMAND_LEN];
+            command_bytes[..3].copy_from_slice(b"inv");
+            message.extend(command_bytes);
+            message.extend((payload.len() as u32).to_be_bytes());
+            message.extend(checksum(payload.as_slice()));
+            message.extend(payload);
+            let _ = decode(message.as_slice());
+        }
+    }
+}
@@ -2,12 +2,21 @@ use std::{collections::HashMap, env, sync::RwLock};
 
 use once_cell::sync::Lazy;
 
-pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(|| Config::new());
+pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(Config::new);
 
 static DEFAULT_NODE_ADDR: &str = "127.0.0.1:2001";
 
 const NODE_ADDRESS_KEY: &str = "NODE_ADDRESS";
 const MINING_ADDRESS_KEY: &str = "MINING_ADDRESS";
+/// Comma separated addresses of nodes to join the network through
+const SEED_NODES_KEY: &str = "SEED_NODES";
+const MAX_INBOUND_KEY: &str = "MAX_INBOUND_CONNECTIONS";
+const MAX_OUTBOUND_KEY: &str = "MAX_OUTBOUND_CONNECTIONS";
+/// Where the admin API listens; it is off unless this is set
+const ADMIN_ADDRESS_KEY: &str = "ADMIN_ADDRESS";
+
+const DEFAULT_MAX_INBOUND: usize = 16;
+const DEFAULT_MAX_OUTBOUND: usize = 8;
 
 pub struct Config {
     inner: RwLock<HashMap<String, String>>,
@@ -21,6 +30,13 @@ impl Config {
         }
         let mut map = HashMap::new();
         map.insert(String::from(NODE_ADDRESS_KEY), node_addr);
+        let seed_nodes = env::var(SEED_NODES_KEY).unwrap_or(String::from(DEFAULT_NODE_ADDR));
+        map.insert(String::from(SEED_NODES_KEY), seed_nodes);
+        for key in [MAX_INBOUND_KEY, MAX_OUTBOUND_KEY, ADMIN_ADDRESS_KEY] {
+            if let Ok(value) = env::var(key) {
+                map.insert(String::from(key), value);
+            }
+        }
 
         Config {
             inner: RwLock::new(map),
@@ -39,7 +55,42 @@ impl Config {
 
     pub fn get_mining_addr(&self) -> Option<String> {
         let inner = self.inner.read().unwrap();
-        inner.get(MINING_ADDRESS_KEY).map(|d| d.clone())
+        inner.get(MINING_ADDRESS_KEY).cloned()
+    }
+
+    pub fn get_seed_nodes(&self) -> Vec<String> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .get(SEED_NODES_KEY)
+            .unwrap()
+            .split(',')
+            .map(|addr| addr.trim())
+            .filter(|addr| !addr.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// Most connections peers may open to us
+    pub fn get_max_inbound(&self) -> usize {
+        self.get_limit(MAX_INBOUND_KEY, DEFAULT_MAX_INBOUND)
+    }
+
+    /// Most connections we open to peers
+    pub fn get_max_outbound(&self) -> usize {
+        self.get_limit(MAX_OUTBOUND_KEY, DEFAULT_MAX_OUTBOUND)
+    }
+
+    fn get_limit(&self, key: &str, default: usize) -> usize {
+        let inner = self.inner.read().unwrap();
+        inner
+            .get(key)
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(default)
+    }
+
+    pub fn get_admin_addr(&self) -> Option<String> {
+        let inner = self.inner.read().unwrap();
+        inner.get(ADMIN_ADDRESS_KEY).cloned()
     }
 
     pub fn is_miner(&self) -> bool {
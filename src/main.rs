@@ -1,7 +1,12 @@
+mod address_book;
+mod admin;
+mod ban_list;
 mod block;
 mod blockchain;
+mod codec;
 mod config;
 mod memory_pool;
+mod merkle;
 mod node;
 mod proof_of_work;
 mod server;
@@ -9,11 +14,230 @@ mod transaction;
 mod utils;
 mod utxo_set;
 mod wallet;
+mod wallets;
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use blockchain::Blockchain;
+use clap::{Parser, Subcommand};
+use config::GLOBAL_CONFIG;
+use data_encoding::HEXLOWER;
+use server::{send_tx, Server};
+use transaction::Transaction;
+use utxo_set::UTXOSet;
+use wallet::{convert_address, hash_pub_key, validate_address, ADDRESS_CHECK_SUM_LEN};
+use wallets::Wallets;
+
+/// Milliseconds since the Unix epoch
 pub(crate) fn current_timestamp() -> i64 {
-    0
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as i64
+}
+
+#[derive(Debug, Parser)]
+#[command(name = "rust-blockchain")]
+struct Opt {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    #[command(name = "createblockchain", about = "Create a new blockchain")]
+    Createblockchain {
+        #[arg(help = "The address to send genesis block reward to")]
+        address: String,
+    },
+    #[command(name = "createwallet", about = "Create a new wallet")]
+    Createwallet,
+    #[command(
+        name = "getbalance",
+        about = "Get the wallet balance of the target address"
+    )]
+    GetBalance {
+        #[arg(help = "The wallet address")]
+        address: String,
+    },
+    #[command(name = "listaddresses", about = "Print local wallet addresses")]
+    ListAddresses,
+    #[command(name = "send", about = "Add new block to chain")]
+    Send {
+        #[arg(help = "Source wallet address")]
+        from: String,
+        #[arg(help = "Destination wallet address")]
+        to: String,
+        #[arg(help = "Amount to send")]
+        amount: i32,
+        #[arg(long, help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[command(name = "printchain", about = "Print blockchain all block")]
+    Printchain,
+    #[command(name = "reindexutxo", about = "Rebuild UTXO index set")]
+    Reindexutxo,
+    #[command(name = "listpeers", about = "Print the running node's peers")]
+    ListPeers,
+    #[command(name = "listbanned", about = "Print peers banned by the running node")]
+    ListBanned,
+    #[command(name = "ban", about = "Ban a peer IP from the running node")]
+    Ban {
+        #[arg(help = "Peer IP address")]
+        ip: String,
+        #[arg(long, help = "Ban duration in seconds, one day if omitted")]
+        seconds: Option<u64>,
+    },
+    #[command(name = "unban", about = "Lift a ban on the running node")]
+    Unban {
+        #[arg(help = "Peer IP address")]
+        ip: String,
+    },
+    #[command(name = "startnode", about = "Start a node")]
+    StartNode {
+        #[arg(help = "Enable mining mode and send reward to ADDRESS")]
+        miner: Option<String>,
+    },
 }
 
 fn main() {
-    println!("Hello, world!");
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let opt = Opt::parse();
+    match opt.command {
+        Command::Createblockchain { address } => {
+            let blockchain = Blockchain::create_blockchain(address.as_str());
+            let utxo_set = UTXOSet::new(blockchain);
+            utxo_set.reindex();
+            println!("Done!");
+        }
+        Command::Createwallet => {
+            let mut wallet = Wallets::new();
+            let address = wallet.create_wallet();
+            println!("Your new address: {}", address)
+        }
+        Command::GetBalance { address } => {
+            if !validate_address(address.as_str()) {
+                panic!("ERROR: Address is not valid")
+            }
+            let payload = utils::base58_decode(address.as_str());
+            let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+
+            let blockchain = Blockchain::new_blockchain();
+            let utxo_set = UTXOSet::new(blockchain);
+            let utxos = utxo_set.find_utxo(pub_key_hash);
+            let mut balance = 0;
+            for utxo in utxos {
+                balance += utxo.get_value();
+            }
+            println!("Balance of {}: {}", address, balance);
+        }
+        Command::ListAddresses => {
+            let wallets = Wallets::new();
+            for address in wallets.get_addresses() {
+                println!("{}", address)
+            }
+        }
+        Command::Send {
+            from,
+            to,
+            amount,
+            mine,
+        } => {
+            if !validate_address(from.as_str()) {
+                panic!("ERROR: Sender address is not valid")
+            }
+            if !validate_address(to.as_str()) {
+                panic!("ERROR: Recipient address is not valid")
+            }
+            let blockchain = Blockchain::new_blockchain();
+            let utxo_set = UTXOSet::new(blockchain.clone());
+
+            let transaction =
+                Transaction::new_utxo_transaction(from.as_str(), to.as_str(), amount, &utxo_set);
+
+            if mine {
+                let coinbase_tx = Transaction::new_coinbase_tx(from.as_str());
+                if let Err(e) = blockchain.mine_block(&[transaction, coinbase_tx]) {
+                    panic!("ERROR: Unable to mine the block: {}", e)
+                }
+            } else {
+                for seed in GLOBAL_CONFIG.get_seed_nodes() {
+                    send_tx(seed.as_str(), &transaction);
+                }
+            }
+            println!("Success!")
+        }
+        Command::Printchain => {
+            let blockchain = Blockchain::new_blockchain();
+            for block in blockchain.iterator() {
+                println!("Pre block hash: {}", block.get_pre_block_hash());
+                println!("Cur block hash: {}", block.get_hash());
+                println!("Cur block Timestamp: {}", block.get_timestamp());
+                println!("Cur block height: {}", block.get_height());
+                for tx in block.get_transactions() {
+                    let cur_txid_hex = HEXLOWER.encode(tx.get_id());
+                    println!("- Transaction txid_hex: {}", cur_txid_hex);
+
+                    if !tx.is_coinbase() {
+                        for input in tx.get_vin() {
+                            let txid_hex = HEXLOWER.encode(input.get_txid());
+                            let pub_key_hash = hash_pub_key(input.get_pub_key());
+                            let address = convert_address(pub_key_hash.as_slice());
+                            println!(
+                                "-- Input txid = {}, vout = {}, from = {}",
+                                txid_hex,
+                                input.get_vout(),
+                                address,
+                            )
+                        }
+                    }
+                    for output in tx.get_vout() {
+                        let pub_key_hash = output.get_pub_key_hash();
+                        let address = convert_address(pub_key_hash);
+                        println!("-- Output value = {}, to = {}", output.get_value(), address,)
+                    }
+                }
+                println!()
+            }
+        }
+        Command::Reindexutxo => {
+            let blockchain = Blockchain::new_blockchain();
+            let utxo_set = UTXOSet::new(blockchain);
+            utxo_set.reindex();
+            let count = utxo_set.count_transactions();
+            println!("Done! There are {} transactions in the UTXO set.", count);
+        }
+        Command::ListPeers => admin_request("listpeers"),
+        Command::ListBanned => admin_request("listbanned"),
+        Command::Ban { ip, seconds } => match seconds {
+            Some(seconds) => admin_request(format!("ban {} {}", ip, seconds).as_str()),
+            None => admin_request(format!("ban {}", ip).as_str()),
+        },
+        Command::Unban { ip } => admin_request(format!("unban {}", ip).as_str()),
+        Command::StartNode { miner } => {
+            if let Some(addr) = miner {
+                if !validate_address(addr.as_str()) {
+                    panic!("Wrong miner address!")
+                }
+                println!("Mining is on. Address to receive rewards: {}", addr);
+                GLOBAL_CONFIG.set_mining_addr(addr);
+            }
+            let blockchain = Blockchain::new_blockchain();
+            let socket_addr = GLOBAL_CONFIG.get_node_addr();
+            Server::new(blockchain).run(socket_addr.as_str());
+        }
+    }
+}
+
+/// Runs a command on the node whose admin API is at `ADMIN_ADDRESS`
+fn admin_request(command: &str) {
+    let admin_addr = GLOBAL_CONFIG
+        .get_admin_addr()
+        .expect("ERROR: Set ADMIN_ADDRESS to the node's admin API address");
+    match admin::request(admin_addr.as_str(), command) {
+        Ok(reply) => print!("{}", reply),
+        Err(e) => panic!("ERROR: Admin API at {} failed: {}", admin_addr, e),
+    }
 }
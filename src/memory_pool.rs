@@ -1,82 +1,315 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
 
 use data_encoding::HEXLOWER;
 
-use crate::transaction::Transaction;
+use crate::{block::Block, transaction::Transaction, utxo_set::outpoint_key};
 
 pub struct MemoryPool {
-    inner: RwLock<HashMap<String, Transaction>>,
+    inner: RwLock<PooledTransactions>,
+}
+
+#[derive(Default)]
+struct PooledTransactions {
+    transactions: HashMap<String, Transaction>,
+    /// Outpoint -> txid of the pooled transaction spending it
+    spent: HashMap<Vec<u8>, String>,
+}
+
+impl PooledTransactions {
+    fn remove(&mut self, txid_hex: &str) {
+        if let Some(tx) = self.transactions.remove(txid_hex) {
+            for outpoint in spent_outpoints(&tx) {
+                self.spent.remove(&outpoint);
+            }
+        }
+    }
+}
+
+fn spent_outpoints(tx: &Transaction) -> Vec<Vec<u8>> {
+    tx.get_vin()
+        .iter()
+        .map(|vin| outpoint_key(vin.get_txid(), vin.get_vout()))
+        .collect()
 }
 
 impl MemoryPool {
     pub fn new() -> Self {
         MemoryPool {
-            inner: RwLock::new(HashMap::new()),
+            inner: RwLock::new(PooledTransactions::default()),
         }
     }
 
     pub fn contains(&self, txid_hex: &str) -> bool {
-        self.inner.read().unwrap().contains_key(txid_hex)
+        self.inner
+            .read()
+            .unwrap()
+            .transactions
+            .contains_key(txid_hex)
     }
 
-    pub fn add(&self, tx: Transaction) {
+    /// Adds `tx` unless it is pooled already or spends an output a pooled
+    /// transaction spends. Returns whether it was added.
+    pub fn add(&self, tx: Transaction) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        let outpoints = spent_outpoints(&tx);
+        if outpoints
+            .iter()
+            .any(|outpoint| inner.spent.contains_key(outpoint))
+        {
+            return false;
+        }
         let txid_hex = HEXLOWER.encode(tx.get_id());
-        self.inner.write().unwrap().insert(txid_hex, tx);
+        if inner.transactions.contains_key(txid_hex.as_str()) {
+            return false;
+        }
+        for outpoint in outpoints {
+            inner.spent.insert(outpoint, txid_hex.clone());
+        }
+        inner.transactions.insert(txid_hex, tx);
+        true
     }
 
     pub fn get(&self, txid_hex: &str) -> Option<Transaction> {
-        self.inner.read().unwrap().get(txid_hex).cloned()
+        self.inner
+            .read()
+            .unwrap()
+            .transactions
+            .get(txid_hex)
+            .cloned()
     }
 
-    pub fn remove(&self, txid_hex: &str) {
-        self.inner.write().unwrap().remove(txid_hex);
+    /// Drops a transaction that can no longer be mined
+    pub fn remove(&self, tx: &Transaction) {
+        let mut inner = self.inner.write().unwrap();
+        inner.remove(HEXLOWER.encode(tx.get_id()).as_str());
+    }
+
+    /// Drops a transaction a block confirmed, along with any pooled one that spends
+    /// the same outputs and so can no longer be mined
+    pub fn remove_confirmed(&self, tx: &Transaction) {
+        let mut inner = self.inner.write().unwrap();
+        inner.remove(HEXLOWER.encode(tx.get_id()).as_str());
+        for outpoint in spent_outpoints(tx) {
+            if let Some(txid_hex) = inner.spent.get(&outpoint).cloned() {
+                inner.remove(txid_hex.as_str());
+            }
+        }
     }
 
     pub fn get_all(&self) -> Vec<Transaction> {
         let inner = self.inner.read().unwrap();
-        let mut output = Vec::with_capacity(inner.len());
-        for tx in inner.values() {
-            output.push(tx.clone());
-        }
-        output
+        inner.transactions.values().cloned().collect()
     }
 
     pub fn len(&self) -> usize {
-        self.inner.read().unwrap().len()
+        self.inner.read().unwrap().transactions.len()
     }
 }
 
+/// Blocks whose headers were accepted but whose bodies still have to be downloaded,
+/// and which peer each requested body is expected from
 pub struct BlockInTransit {
-    inner: RwLock<Vec<Vec<u8>>>,
+    inner: RwLock<BlockDownloads>,
+}
+
+#[derive(Default)]
+struct BlockDownloads {
+    /// `(hash, height)` pairs not requested yet, lowest height first
+    queued: Vec<(Vec<u8>, usize)>,
+    in_flight: HashMap<Vec<u8>, InFlightBlock>,
+}
+
+struct InFlightBlock {
+    height: usize,
+    peer: String,
+    requested_at: Instant,
 }
 
 impl BlockInTransit {
     pub fn new() -> BlockInTransit {
         BlockInTransit {
-            inner: RwLock::new(Vec::new()),
+            inner: RwLock::new(BlockDownloads::default()),
         }
     }
 
-    pub fn add_blocks(&self, blocks: Vec<Vec<u8>>) {
-        self.inner.write().unwrap().extend(blocks);
+    /// Queues `(hash, height)` pairs that are neither queued nor requested already
+    pub fn add_blocks(&self, blocks: Vec<(Vec<u8>, usize)>) {
+        let mut inner = self.inner.write().unwrap();
+        for (hash, height) in blocks {
+            let known = inner.in_flight.contains_key(&hash)
+                || inner.queued.iter().any(|(queued, _)| queued.eq(&hash));
+            if !known {
+                inner.queued.push((hash, height));
+            }
+        }
+        inner.queued.sort_by_key(|(_, height)| *height);
     }
 
-    pub fn first(&self) -> Option<Vec<u8>> {
-        self.inner.read().unwrap().first().cloned()
+    /// Takes queued blocks `peer` can serve, at most `best_height`, until it has `max`
+    /// requests outstanding, and marks them as requested from it
+    pub fn assign(&self, peer: &str, best_height: usize, max: usize) -> Vec<Vec<u8>> {
+        let mut inner = self.inner.write().unwrap();
+        let outstanding = inner
+            .in_flight
+            .values()
+            .filter(|block| block.peer.eq(peer))
+            .count();
+        let mut assigned = vec![];
+        let mut index = 0;
+        while outstanding + assigned.len() < max && index < inner.queued.len() {
+            if inner.queued[index].1 > best_height {
+                index += 1;
+                continue;
+            }
+            let (hash, height) = inner.queued.remove(index);
+            let block = InFlightBlock {
+                height,
+                peer: peer.to_string(),
+                requested_at: Instant::now(),
+            };
+            inner.in_flight.insert(hash.clone(), block);
+            assigned.push(hash);
+        }
+        assigned
     }
 
+    /// Forgets a block once its body has arrived
     pub fn remove(&self, block_hash: &[u8]) {
         let mut inner = self.inner.write().unwrap();
-        if let Some(index) = inner.iter().position(|x| x.eq(block_hash)) {
-            inner.remove(index);
+        inner.in_flight.remove(block_hash);
+        if let Some(index) = inner.queued.iter().position(|(x, _)| x.eq(block_hash)) {
+            inner.queued.remove(index);
         }
     }
 
-    pub fn clear(&self) {
-        self.inner.write().unwrap().clear();
+    /// Queues requests that went unanswered for `timeout` again, so another peer can
+    /// be asked
+    pub fn expire(&self, timeout: Duration) {
+        let mut inner = self.inner.write().unwrap();
+        let expired: Vec<Vec<u8>> = inner
+            .in_flight
+            .iter()
+            .filter(|(_, block)| block.requested_at.elapsed() >= timeout)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in expired {
+            let block = inner.in_flight.remove(&hash).unwrap();
+            inner.queued.push((hash, block.height));
+        }
+        inner.queued.sort_by_key(|(_, height)| *height);
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.inner.read().unwrap().len()
+/// Orphans kept at once. Each one costs a full block body of memory, and a peer
+/// can send them faster than their parents arrive.
+const MAX_ORPHAN_BLOCKS: usize = 100;
+
+/// Downloaded blocks that arrived before their parent's body, keyed by parent hash
+pub struct OrphanBlocks {
+    inner: RwLock<WaitingBlocks>,
+}
+
+#[derive(Default)]
+struct WaitingBlocks {
+    children: HashMap<String, Vec<Block>>,
+    /// (parent hash, block hash) of every orphan, oldest first
+    arrival: VecDeque<(String, String)>,
+}
+
+impl OrphanBlocks {
+    pub fn new() -> OrphanBlocks {
+        OrphanBlocks {
+            inner: RwLock::new(WaitingBlocks::default()),
+        }
+    }
+
+    /// Holds `block` until its parent is connected, evicting the oldest orphan
+    /// once `MAX_ORPHAN_BLOCKS` are waiting
+    pub fn add(&self, block: Block) {
+        let mut inner = self.inner.write().unwrap();
+        let parent_hash = block.get_pre_block_hash();
+        let children = inner.children.entry(parent_hash.clone()).or_default();
+        if children.iter().any(|x| x.get_hash() == block.get_hash()) {
+            return;
+        }
+        let block_hash = block.get_hash().to_string();
+        children.push(block);
+        inner.arrival.push_back((parent_hash, block_hash));
+        while inner.arrival.len() > MAX_ORPHAN_BLOCKS {
+            let (parent_hash, block_hash) = inner.arrival.pop_front().unwrap();
+            if let Some(children) = inner.children.get_mut(&parent_hash) {
+                children.retain(|x| x.get_hash() != block_hash);
+                if children.is_empty() {
+                    inner.children.remove(&parent_hash);
+                }
+            }
+        }
+    }
+
+    /// Removes and returns the waiting children of `block_hash`
+    pub fn take_children(&self, block_hash: &str) -> Vec<Block> {
+        let mut inner = self.inner.write().unwrap();
+        let children = inner.children.remove(block_hash).unwrap_or_default();
+        if !children.is_empty() {
+            inner
+                .arrival
+                .retain(|(parent_hash, _)| parent_hash != block_hash);
+        }
+        children
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proof_of_work::initial_bits, transaction::TXOutput, wallet::Wallet};
+
+    fn pay(prev_tx: &Transaction, from: &Wallet, to: &Wallet) -> Transaction {
+        let outputs = vec![TXOutput::new(1, &to.get_address())];
+        Transaction::new_test_spend(prev_tx, 0, from, outputs)
+    }
+
+    #[test]
+    fn second_spend_of_an_output_is_rejected() {
+        let alice = Wallet::new();
+        let coinbase = Transaction::new_coinbase_tx(&alice.get_address());
+        let pool = MemoryPool::new();
+        assert!(pool.add(pay(&coinbase, &alice, &Wallet::new())));
+        assert!(!pool.add(pay(&coinbase, &alice, &Wallet::new())));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn confirmed_spend_drops_its_conflicts() {
+        let alice = Wallet::new();
+        let coinbase = Transaction::new_coinbase_tx(&alice.get_address());
+        let pool = MemoryPool::new();
+        let pooled = pay(&coinbase, &alice, &Wallet::new());
+        assert!(pool.add(pooled.clone()));
+
+        let confirmed = pay(&coinbase, &alice, &Wallet::new());
+        pool.remove_confirmed(&confirmed);
+        assert!(!pool.contains(HEXLOWER.encode(pooled.get_id()).as_str()));
+        // The output is free again, so a new spend is accepted
+        assert!(pool.add(pooled));
+    }
+
+    #[test]
+    fn oldest_orphan_is_evicted_when_the_pool_is_full() {
+        let orphans = OrphanBlocks::new();
+        let parent_hash = |i: usize| format!("{:064x}", i);
+        for i in 0..=MAX_ORPHAN_BLOCKS {
+            orphans.add(Block::new_block(parent_hash(i), &[], 1, initial_bits(), 0));
+        }
+        assert!(orphans.take_children(&parent_hash(0)).is_empty());
+        assert_eq!(orphans.take_children(&parent_hash(1)).len(), 1);
+        assert_eq!(
+            orphans.take_children(&parent_hash(MAX_ORPHAN_BLOCKS)).len(),
+            1
+        );
     }
 }
@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::sha256_digest;
+
+const HASH_LEN: usize = 32;
+
+/// Bitcoin-style Merkle root: pairs are hashed with double SHA-256 level by level, and
+/// an odd node at the end of a level is paired with itself.
+pub fn merkle_root(leaves: &[Vec<u8>]) -> Vec<u8> {
+    if leaves.is_empty() {
+        return vec![0; HASH_LEN];
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(level.as_slice());
+    }
+    level.remove(0)
+}
+
+/// The sibling hashes needed to recompute the root from the leaf at `index`
+pub fn merkle_branch(leaves: &[Vec<u8>], index: usize) -> Option<MerkleBranch> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut hashes = vec![];
+    let mut level = leaves.to_vec();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        let sibling_hash = level.get(sibling).unwrap_or(&level[position]);
+        hashes.push(sibling_hash.clone());
+        level = next_level(level.as_slice());
+        position /= 2;
+    }
+    Some(MerkleBranch { index, hashes })
+}
+
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level
+        .chunks(2)
+        .map(|pair| {
+            let right = pair.get(1).unwrap_or(&pair[0]);
+            hash_pair(pair[0].as_slice(), right.as_slice())
+        })
+        .collect()
+}
+
+fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut data = left.to_vec();
+    data.extend(right);
+    sha256_digest(sha256_digest(data.as_slice()).as_slice())
+}
+
+/// Proof that a leaf is included under a Merkle root
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MerkleBranch {
+    index: usize,
+    hashes: Vec<Vec<u8>>,
+}
+
+impl MerkleBranch {
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    pub fn get_hashes(&self) -> &[Vec<u8>] {
+        self.hashes.as_slice()
+    }
+
+    pub fn verify(&self, leaf: &[u8], root: &[u8]) -> bool {
+        let mut hash = leaf.to_vec();
+        let mut position = self.index;
+        for sibling in &self.hashes {
+            hash = if position.is_multiple_of(2) {
+                hash_pair(hash.as_slice(), sibling.as_slice())
+            } else {
+                hash_pair(sibling.as_slice(), hash.as_slice())
+            };
+            position /= 2;
+        }
+        position == 0 && hash.eq(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block::Block, proof_of_work::initial_bits, transaction::Transaction};
+
+    fn leaves(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i; HASH_LEN]).collect()
+    }
+
+    #[test]
+    fn roots_of_small_trees() {
+        let l = leaves(4);
+        let h = |a: &[u8], b: &[u8]| hash_pair(a, b);
+        assert_eq!(merkle_root(&l[..1]), l[0]);
+        assert_eq!(merkle_root(&l[..2]), h(&l[0], &l[1]));
+        // The odd leaf is paired with itself
+        let root_3 = h(&h(&l[0], &l[1]), &h(&l[2], &l[2]));
+        assert_eq!(merkle_root(&l[..3]), root_3);
+        let root_4 = h(&h(&l[0], &l[1]), &h(&l[2], &l[3]));
+        assert_eq!(merkle_root(&l[..4]), root_4);
+    }
+
+    #[test]
+    fn branch_verifies_for_every_index() {
+        for count in 1..=9 {
+            let l = leaves(count);
+            let root = merkle_root(&l);
+            for (index, leaf) in l.iter().enumerate() {
+                let branch = merkle_branch(&l, index).unwrap();
+                assert!(branch.verify(leaf, &root), "{} of {}", index, count);
+            }
+            assert!(merkle_branch(&l, l.len()).is_none());
+        }
+    }
+
+    #[test]
+    fn tampered_sibling_or_wrong_index_is_rejected() {
+        let l = leaves(5);
+        let root = merkle_root(&l);
+        let branch = merkle_branch(&l, 2).unwrap();
+
+        for level in 0..branch.hashes.len() {
+            let mut tampered = branch.clone();
+            tampered.hashes[level][0] ^= 1;
+            assert!(!tampered.verify(&l[2], &root));
+        }
+        for index in [0, 1, 3, 4, 6] {
+            let moved = MerkleBranch {
+                index,
+                hashes: branch.hashes.clone(),
+            };
+            assert!(!moved.verify(&l[2], &root), "accepted index {}", index);
+        }
+        assert!(!branch.verify(&l[3], &root));
+    }
+
+    #[test]
+    fn block_branch_proves_a_transaction() {
+        let transactions: Vec<Transaction> = (0..3)
+            .map(|_| Transaction::new_coinbase_tx("1BoatSLRHtKNngkdXEeobR76b53LETtpyT"))
+            .collect();
+        let block = Block::new_block(
+            String::from("None"),
+            &transactions,
+            0,
+            initial_bits(),
+            crate::current_timestamp(),
+        );
+        let root = block.get_header().get_merkle_root().to_vec();
+        for tx in &transactions {
+            let branch = block.get_merkle_branch(tx.get_id()).unwrap();
+            assert!(branch.verify(tx.get_id(), &root));
+        }
+        assert!(block.get_merkle_branch(&[0; HASH_LEN]).is_none());
+    }
+}
@@ -1,32 +1,154 @@
-use std::{net::SocketAddr, sync::RwLock};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Sender,
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// An open session with a peer. Frames passed to `send` are queued for the
+/// connection's writer thread.
+#[derive(Clone)]
+pub struct Connection {
+    id: u64,
+    direction: Direction,
+    /// Address the peer's socket connects from
+    ip: IpAddr,
+    sender: Sender<Vec<u8>>,
+    stream: Arc<TcpStream>,
+}
+
+impl Connection {
+    pub fn new(
+        direction: Direction,
+        ip: IpAddr,
+        sender: Sender<Vec<u8>>,
+        stream: TcpStream,
+    ) -> Connection {
+        Connection {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            direction,
+            ip,
+            sender,
+            stream: Arc::new(stream),
+        }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn get_ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Queues an encoded message. Returns false once the writer has shut down.
+    pub fn send(&self, frame: Vec<u8>) -> bool {
+        self.sender.send(frame).is_ok()
+    }
+
+    /// Shuts the socket down, which ends the session's reader and writer threads
+    pub fn close(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
 
 #[derive(Clone)]
 pub struct Node {
     addr: String,
+    /// Highest block the peer is known to have, from its version message or headers
+    best_height: usize,
+    connection: Option<Connection>,
+    /// When the current session last received anything from the peer
+    last_seen: Option<Instant>,
+    /// Nonce and send time of the ping awaiting a pong
+    pending_ping: Option<(u64, Instant)>,
+    /// Whether we asked the peer for addresses and are waiting for its `Addr`
+    addr_requested: bool,
+    /// Round-trip time of the last answered ping
+    rtt: Option<Duration>,
 }
 
 impl Node {
     fn new(addr: String) -> Node {
-        Node { addr }
+        Node {
+            addr,
+            best_height: 0,
+            connection: None,
+            last_seen: None,
+            pending_ping: None,
+            addr_requested: false,
+            rtt: None,
+        }
     }
 
     pub fn get_addr(&self) -> String {
         self.addr.clone()
     }
 
-    pub fn parse_socket_addr(&self) -> SocketAddr {
-        self.addr.parse().unwrap()
+    pub fn get_best_height(&self) -> usize {
+        self.best_height
+    }
+
+    /// IP of the open session, or else the one in the peer's listening address
+    pub fn get_ip(&self) -> Option<IpAddr> {
+        match &self.connection {
+            Some(connection) => Some(connection.get_ip()),
+            None => self.addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()),
+        }
+    }
+
+    pub fn get_connection(&self) -> Option<&Connection> {
+        self.connection.as_ref()
+    }
+
+    pub fn get_last_seen(&self) -> Option<Instant> {
+        self.last_seen
+    }
+
+    pub fn get_rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// True once the session has been silent, or a ping unanswered, for `timeout`
+    fn is_stale(&self, timeout: Duration) -> bool {
+        let silent = self
+            .last_seen
+            .is_some_and(|last_seen| last_seen.elapsed() >= timeout);
+        let unanswered = self
+            .pending_ping
+            .is_some_and(|(_, sent_at)| sent_at.elapsed() >= timeout);
+        silent || unanswered
     }
 }
 
 pub struct Nodes {
     inner: RwLock<Vec<Node>>,
+    /// Points for misbehaviour per peer IP; the IP is banned once its score reaches
+    /// the threshold
+    ban_scores: RwLock<HashMap<IpAddr, u32>>,
 }
 
 impl Nodes {
     pub fn new() -> Nodes {
         Nodes {
             inner: RwLock::new(Vec::new()),
+            ban_scores: RwLock::new(HashMap::new()),
         }
     }
 
@@ -48,21 +170,168 @@ impl Nodes {
         }
     }
 
-    pub fn first(&self) -> Option<Node> {
+    /// Makes `connection` the session used for `addr`, adding the node if it is new.
+    /// Returns false, leaving things unchanged, if the node is already connected on
+    /// another session.
+    pub fn connect(&self, addr: &str, connection: Connection) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        let node = match inner.iter().position(|x| x.get_addr().eq(addr)) {
+            Some(pos) => &mut inner[pos],
+            None => {
+                inner.push(Node::new(addr.to_string()));
+                inner.last_mut().unwrap()
+            }
+        };
+        if let Some(existing) = node.connection.as_ref() {
+            return existing.get_id() == connection.get_id();
+        }
+        node.connection = Some(connection);
+        node.last_seen = Some(Instant::now());
+        node.pending_ping = None;
+        true
+    }
+
+    /// Marks `addr` as disconnected, unless it has since moved to another connection
+    /// than `connection_id`
+    pub fn disconnect(&self, addr: &str, connection_id: u64) {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(node) = inner.iter_mut().find(|x| x.get_addr().eq(addr)) {
+            if node
+                .connection
+                .as_ref()
+                .is_some_and(|connection| connection.get_id() == connection_id)
+            {
+                node.connection = None;
+            }
+        }
+    }
+
+    /// Records that the session with `addr` just received something
+    pub fn touch(&self, addr: &str) {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(node) = inner.iter_mut().find(|x| x.get_addr().eq(addr)) {
+            node.last_seen = Some(Instant::now());
+        }
+    }
+
+    /// Records a ping sent to `addr`. Returns false, keeping the earlier one, if a
+    /// ping is still awaiting its pong.
+    pub fn start_ping(&self, addr: &str, nonce: u64) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        match inner.iter_mut().find(|x| x.get_addr().eq(addr)) {
+            Some(node) if node.pending_ping.is_none() => {
+                node.pending_ping = Some((nonce, Instant::now()));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Matches a pong from `addr` to its ping and returns the round-trip time, or
+    /// `None` if `nonce` is not the one awaited
+    pub fn complete_ping(&self, addr: &str, nonce: u64) -> Option<Duration> {
+        let mut inner = self.inner.write().unwrap();
+        let node = inner.iter_mut().find(|x| x.get_addr().eq(addr))?;
+        let (expected, sent_at) = node.pending_ping?;
+        if expected != nonce {
+            return None;
+        }
+        let rtt = sent_at.elapsed();
+        node.pending_ping = None;
+        node.rtt = Some(rtt);
+        Some(rtt)
+    }
+
+    /// Records that `addr` was asked for addresses, so its next `Addr` is expected
+    pub fn request_addr(&self, addr: &str) {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(node) = inner.iter_mut().find(|x| x.get_addr().eq(addr)) {
+            node.addr_requested = true;
+        }
+    }
+
+    /// Matches an `Addr` from `addr` to our request. Returns false if it was sent
+    /// unasked.
+    pub fn complete_addr_request(&self, addr: &str) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        inner
+            .iter_mut()
+            .find(|x| x.get_addr().eq(addr))
+            .is_some_and(|node| std::mem::take(&mut node.addr_requested))
+    }
+
+    /// Open sessions with peers that have been silent, or left a ping unanswered,
+    /// for `timeout`
+    pub fn get_stale_connections(&self, timeout: Duration) -> Vec<(String, Connection)> {
         let inner = self.inner.read().unwrap();
-        inner.first().map(|d| d.clone())
+        inner
+            .iter()
+            .filter(|node| node.is_stale(timeout))
+            .filter_map(|node| Some((node.get_addr(), node.connection.clone()?)))
+            .collect()
     }
 
-    pub fn get_nodes(&self) -> Vec<Node> {
-        self.inner.read().unwrap().to_vec()
+    pub fn get_connection(&self, addr: &str) -> Option<Connection> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .iter()
+            .find(|x| x.get_addr().eq(addr))
+            .and_then(|node| node.connection.clone())
     }
 
-    pub fn len(&self) -> usize {
-        self.inner.read().unwrap().len()
+    /// Open sessions with peers connecting from `ip`, with the address of each
+    pub fn get_connections_from(&self, ip: IpAddr) -> Vec<(String, Connection)> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .iter()
+            .filter_map(|node| Some((node.get_addr(), node.connection.clone()?)))
+            .filter(|(_, connection)| connection.get_ip() == ip)
+            .collect()
     }
 
-    pub fn node_is_known(&self, addr: &str) -> bool {
+    /// Number of open sessions in `direction`
+    pub fn count_connections(&self, direction: Direction) -> usize {
         let inner = self.inner.read().unwrap();
-        inner.iter().find(|x| x.get_addr().eq(addr)).is_some()
+        inner
+            .iter()
+            .filter_map(|node| node.connection.as_ref())
+            .filter(|connection| connection.get_direction() == direction)
+            .count()
+    }
+
+    /// Records that `addr` has blocks up to at least `best_height`
+    pub fn update_best_height(&self, addr: &str, best_height: usize) {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(node) = inner.iter_mut().find(|x| x.get_addr().eq(addr)) {
+            node.best_height = node.best_height.max(best_height);
+        }
+    }
+
+    /// Adds `points` to the ban score of `ip` and returns the new score
+    pub fn add_ban_score(&self, ip: IpAddr, points: u32) -> u32 {
+        let mut ban_scores = self.ban_scores.write().unwrap();
+        let score = ban_scores.entry(ip).or_insert(0);
+        *score = score.saturating_add(points);
+        *score
+    }
+
+    pub fn get_ban_score(&self, ip: IpAddr) -> u32 {
+        let ban_scores = self.ban_scores.read().unwrap();
+        ban_scores.get(&ip).copied().unwrap_or(0)
+    }
+
+    pub fn clear_ban_score(&self, ip: IpAddr) {
+        self.ban_scores.write().unwrap().remove(&ip);
+    }
+
+    pub fn get_nodes(&self) -> Vec<Node> {
+        self.inner.read().unwrap().to_vec()
+    }
+
+    /// Every node, lowest round-trip time first and unmeasured ones last
+    pub fn get_nodes_by_latency(&self) -> Vec<Node> {
+        let mut nodes = self.get_nodes();
+        nodes.sort_by_key(|node| node.get_rtt().unwrap_or(Duration::MAX));
+        nodes
     }
 }
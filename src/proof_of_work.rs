@@ -1,22 +1,35 @@
 use std::borrow::Borrow;
 
 use data_encoding::HEXLOWER;
-use num::{bigint::Sign, BigInt};
+use num::{bigint::Sign, BigInt, One, Zero};
 
-use crate::block::Block;
+use crate::{block::BlockHeader, utils::sha256_digest};
 
 const MAX_NONCE: i64 = i64::MAX;
 
+/// Number of leading zero bits the genesis block hash must have. This is also the
+/// easiest difficulty retargeting may fall back to.
+pub const TARGET_BITS: u32 = 8;
+
+/// Difficulty is recomputed every `RETARGET_INTERVAL` blocks
+pub const RETARGET_INTERVAL: usize = 10;
+
+/// Expected time between blocks in milliseconds
+pub const TARGET_BLOCK_TIME: i64 = 10_000;
+
+/// A single retarget can make mining at most this many times easier or harder
+const MAX_ADJUSTMENT_FACTOR: i64 = 4;
+
 pub struct ProofOfWork {
-    block: Block,
+    header: BlockHeader,
     target: BigInt,
 }
 
 impl ProofOfWork {
-    pub fn new_proof_of_work(block: Block) -> Self {
+    pub fn new_proof_of_work(header: BlockHeader) -> Self {
         Self {
-            block,
-            target: BigInt::from(0),
+            target: compact_to_target(header.get_bits()),
+            header,
         }
     }
 
@@ -26,7 +39,7 @@ impl ProofOfWork {
         println!("Mining the block");
         while nonce < MAX_NONCE {
             let data = self.prepare_data(nonce);
-            hash = sha256::digest(data.as_slice()).into();
+            hash = sha256_digest(data.as_slice());
             let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
             if hash_int.lt(self.target.borrow()) {
                 println!("{}", HEXLOWER.encode(hash.as_slice()));
@@ -39,7 +52,179 @@ impl ProofOfWork {
         (nonce, HEXLOWER.encode(hash.as_slice()))
     }
 
+    /// Checks the header's stored nonce against the target without mining again
+    pub fn validate(&self) -> bool {
+        let data = self.prepare_data(self.header.get_nonce());
+        let hash = sha256_digest(data.as_slice());
+        let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
+        hash_int.lt(self.target.borrow())
+    }
+
+    /// Hex encoded hash of the header with `nonce`
+    pub fn hash(&self, nonce: i64) -> String {
+        HEXLOWER.encode(sha256_digest(self.prepare_data(nonce).as_slice()).as_slice())
+    }
+
+    /// The header bytes the hash commits to
     pub fn prepare_data(&self, nonce: i64) -> Vec<u8> {
-        Vec::new()
+        let mut data_bytes = vec![];
+        data_bytes.extend(self.header.get_version().to_be_bytes());
+        data_bytes.extend(self.header.get_pre_block_hash().as_bytes());
+        data_bytes.extend(self.header.get_merkle_root());
+        data_bytes.extend(self.header.get_timestamp().to_be_bytes());
+        data_bytes.extend(self.header.get_bits().to_be_bytes());
+        data_bytes.extend((self.header.get_height() as u64).to_be_bytes());
+        data_bytes.extend(nonce.to_be_bytes());
+        data_bytes
+    }
+}
+
+/// A hash is valid when it is below `1 << (256 - bits)`
+pub fn target_from_bits(bits: u32) -> BigInt {
+    BigInt::one() << (256 - bits as usize)
+}
+
+/// Expected number of hashes needed to find a block at this compact target
+pub fn block_work(bits: u32) -> BigInt {
+    (BigInt::one() << 256) / (compact_to_target(bits) + BigInt::one())
+}
+
+/// The compact target of the genesis block
+pub fn initial_bits() -> u32 {
+    target_to_compact(&target_from_bits(TARGET_BITS))
+}
+
+/// Scales the target by how long the last `RETARGET_INTERVAL` blocks actually took
+/// compared to how long they should have taken, Bitcoin-style.
+pub fn retarget(bits: u32, actual_timespan: i64) -> u32 {
+    let expected_timespan = RETARGET_INTERVAL as i64 * TARGET_BLOCK_TIME;
+    let actual_timespan = actual_timespan.clamp(
+        expected_timespan / MAX_ADJUSTMENT_FACTOR,
+        expected_timespan * MAX_ADJUSTMENT_FACTOR,
+    );
+    let mut target = compact_to_target(bits) * actual_timespan / expected_timespan;
+    let pow_limit = target_from_bits(TARGET_BITS);
+    if target > pow_limit {
+        target = pow_limit;
+    }
+    target_to_compact(&target)
+}
+
+/// Decodes Bitcoin's compact "nBits" form: a one byte exponent followed by a three
+/// byte mantissa, so that `target = mantissa * 256^(exponent - 3)`.
+pub fn compact_to_target(bits: u32) -> BigInt {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = BigInt::from(bits & 0x007f_ffff);
+    if exponent <= 3 {
+        mantissa >> (8 * (3 - exponent))
+    } else {
+        mantissa << (8 * (exponent - 3))
+    }
+}
+
+pub fn target_to_compact(target: &BigInt) -> u32 {
+    let (_, bytes) = target.to_bytes_be();
+    if target.is_zero() {
+        return 0;
+    }
+    let mut size = bytes.len();
+    let mut mantissa: u32 = 0;
+    for byte in bytes.iter().take(3) {
+        mantissa = (mantissa << 8) | *byte as u32;
+    }
+    if size < 3 {
+        mantissa <<= 8 * (3 - size);
+    }
+    // The mantissa is signed in Bitcoin's encoding, so keep its top bit clear
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    mantissa | ((size as u32) << 24)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPECTED_TIMESPAN: i64 = RETARGET_INTERVAL as i64 * TARGET_BLOCK_TIME;
+
+    /// Bitcoin's genesis difficulty, well below our pow limit
+    const HARD_BITS: u32 = 0x1d00_ffff;
+
+    #[test]
+    fn initial_bits_round_trip() {
+        assert_eq!(initial_bits(), 0x2001_0000);
+        assert_eq!(
+            compact_to_target(initial_bits()),
+            target_from_bits(TARGET_BITS)
+        );
+    }
+
+    #[test]
+    fn compact_encoding_matches_bitcoin() {
+        assert_eq!(compact_to_target(HARD_BITS), BigInt::from(0xffff) << 208);
+        for bits in [
+            HARD_BITS,
+            0x1b04_04cb,
+            0x0301_2345,
+            0x0200_8000,
+            initial_bits(),
+        ] {
+            assert_eq!(
+                target_to_compact(&compact_to_target(bits)),
+                bits,
+                "{:#x}",
+                bits
+            );
+        }
+        assert_eq!(target_to_compact(&BigInt::zero()), 0);
+        assert_eq!(target_to_compact(&BigInt::from(0x0001_2345)), 0x0301_2345);
+    }
+
+    #[test]
+    fn mantissa_sign_bit_moves_into_the_exponent() {
+        // 0xffff << 208 starts 0xffff00, whose top bit would read as negative
+        assert_eq!(target_to_compact(&(BigInt::from(0xffff) << 208)), HARD_BITS);
+        assert_eq!(target_to_compact(&BigInt::from(0x80)), 0x0200_8000);
+        assert_eq!(compact_to_target(0x0200_8000), BigInt::from(0x80));
+    }
+
+    #[test]
+    fn retarget_follows_the_timespan() {
+        let target = compact_to_target(HARD_BITS);
+        assert_eq!(retarget(HARD_BITS, EXPECTED_TIMESPAN), HARD_BITS);
+        assert_eq!(
+            retarget(HARD_BITS, EXPECTED_TIMESPAN / 2),
+            target_to_compact(&(&target / 2))
+        );
+        assert_eq!(
+            retarget(HARD_BITS, EXPECTED_TIMESPAN * 3),
+            target_to_compact(&(&target * 3))
+        );
+    }
+
+    #[test]
+    fn retarget_is_clamped_to_four_times_either_way() {
+        let target = compact_to_target(HARD_BITS);
+        let hardest = target_to_compact(&(&target / MAX_ADJUSTMENT_FACTOR));
+        let easiest = target_to_compact(&(&target * MAX_ADJUSTMENT_FACTOR));
+        assert_eq!(retarget(HARD_BITS, EXPECTED_TIMESPAN / 4), hardest);
+        assert_eq!(retarget(HARD_BITS, 1), hardest);
+        assert_eq!(retarget(HARD_BITS, 0), hardest);
+        assert_eq!(retarget(HARD_BITS, -EXPECTED_TIMESPAN), hardest);
+        assert_eq!(retarget(HARD_BITS, EXPECTED_TIMESPAN * 4), easiest);
+        assert_eq!(retarget(HARD_BITS, i64::MAX), easiest);
+    }
+
+    #[test]
+    fn retarget_never_goes_past_the_pow_limit() {
+        assert_eq!(
+            retarget(initial_bits(), EXPECTED_TIMESPAN * 4),
+            initial_bits()
+        );
+        let near_limit = target_to_compact(&(target_from_bits(TARGET_BITS) / 2));
+        assert_eq!(retarget(near_limit, EXPECTED_TIMESPAN * 4), initial_bits());
+        assert!(compact_to_target(retarget(initial_bits(), 1)) < target_from_bits(TARGET_BITS));
     }
 }
@@ -1,43 +1,107 @@
 use std::{
     error::Error,
-    io::{BufReader, Write},
-    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    io::{self, BufReader, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
     thread,
     time::Duration,
 };
 
 use data_encoding::HEXLOWER;
-use log::{error, info};
-use once_cell::sync::Lazy;
+use log::{error, info, warn};
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 
 use crate::{
-    block::Block,
-    blockchain::Blockchain,
+    address_book::AddressBook,
+    admin,
+    ban_list::BanList,
+    block::{Block, BlockHeader},
+    blockchain::{BlockValidationError, Blockchain},
+    codec,
     config::GLOBAL_CONFIG,
-    memory_pool::{BlockInTransit, MemoryPool},
-    node::Nodes,
+    memory_pool::{BlockInTransit, MemoryPool, OrphanBlocks},
+    node::{Connection, Direction, Node, Nodes},
     transaction::Transaction,
+    utils,
     utxo_set::UTXOSet,
 };
 
 const NODE_VERSION: usize = 1;
-pub const CENTRAL_NODE: &str = "127.0.0.1:2001";
 
 pub const TRANSACTION_THRESHOLD: usize = 2;
 
-static GLOBAL_NODES: Lazy<Nodes> = Lazy::new(|| {
-    let nodes = Nodes::new();
-    nodes.add_node(String::from(CENTRAL_NODE));
-    return nodes;
-});
+/// Most addresses sent in, or taken from, one `Addr`
+const MAX_ADDR_RESULTS: usize = 1000;
+/// Most headers sent in reply to one `GetHeaders`
+const MAX_HEADERS_RESULTS: usize = 2000;
+/// Most block bodies requested from a single peer at a time
+const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+/// Milliseconds after which an unanswered block request is handed to another peer
+const BLOCK_DOWNLOAD_TIMEOUT: u64 = 30_000;
+/// Milliseconds between pings to each connected peer
+const PING_INTERVAL: u64 = 30_000;
+/// Milliseconds a peer may stay silent, or leave a ping unanswered, before it is
+/// disconnected
+const PEER_TIMEOUT: u64 = 90_000;
+/// Most items accepted in one `Inv`
+const MAX_INV_ITEMS: usize = 50_000;
+/// Most hashes accepted in a block locator
+const MAX_LOCATOR_SIZE: usize = 101;
+
+/// Ban score at which a peer is banned
+const BAN_THRESHOLD: u32 = 100;
+/// Milliseconds a peer stays banned
+pub const DEFAULT_BAN_DURATION: i64 = 24 * 60 * 60 * 1000;
+const INVALID_BLOCK_SCORE: u32 = 100;
+const INVALID_TX_SCORE: u32 = 10;
+const MALFORMED_MESSAGE_SCORE: u32 = 50;
+/// For lists longer than the protocol allows, and addresses nobody asked for
+const SPAM_SCORE: u32 = 20;
+
+static GLOBAL_NODES: Lazy<Nodes> = Lazy::new(Nodes::new);
+
+static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(MemoryPool::new);
 
-static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::new());
+static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(BlockInTransit::new);
 
-static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(|| BlockInTransit::new());
+static GLOBAL_ORPHAN_BLOCKS: Lazy<OrphanBlocks> = Lazy::new(OrphanBlocks::new);
+
+/// Set once the node is serving, so outbound connections can read replies
+static GLOBAL_BLOCKCHAIN: OnceCell<Blockchain> = OnceCell::new();
 
 const TCP_WRITE_TIMEOUT: u64 = 1000;
+/// Milliseconds to wait for a peer to accept a connection
+const TCP_CONNECT_TIMEOUT: u64 = 3000;
+/// Milliseconds an inbound peer has to send its first package
+const HANDSHAKE_TIMEOUT: u64 = 10_000;
+
+/// Inbound sessions from the moment they are accepted until their read loop ends,
+/// including those whose peer has not sent anything yet
+static INBOUND_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the `MAX_INBOUND` session slots, given back when dropped
+struct InboundSlot;
+
+impl InboundSlot {
+    fn acquire(max_inbound: usize) -> Option<InboundSlot> {
+        INBOUND_SESSIONS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < max_inbound).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| InboundSlot)
+    }
+}
+
+impl Drop for InboundSlot {
+    fn drop(&mut self) {
+        INBOUND_SESSIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 pub struct Server {
     blockchain: Blockchain,
@@ -50,17 +114,77 @@ impl Server {
 
     pub fn run(&self, addr: &str) {
         let listener = TcpListener::bind(addr).unwrap();
-        if addr.eq(CENTRAL_NODE) == false {
-            let best_height = self.blockchain.get_best_height();
-            send_version(CENTRAL_NODE, best_height);
+        let _ = GLOBAL_BLOCKCHAIN.set(self.blockchain.clone());
+        if let Some(admin_addr) = GLOBAL_CONFIG.get_admin_addr() {
+            if let Err(e) = admin::start(admin_addr.as_str(), self.blockchain.clone()) {
+                error!("Admin API failed to start on {}: {}", admin_addr, e);
+            }
+        }
+        thread::spawn(|| loop {
+            thread::sleep(Duration::from_millis(PING_INTERVAL));
+            keep_alive();
+        });
+
+        // Join through the configured seeds first, then the peers seen most recently
+        let address_book = AddressBook::new(self.blockchain.get_db().clone());
+        let max_outbound = GLOBAL_CONFIG.get_max_outbound();
+        let mut peers = vec![];
+        for peer in GLOBAL_CONFIG.get_seed_nodes() {
+            if peer.parse::<SocketAddr>().is_err() {
+                warn!("Ignoring invalid seed node {:?}", peer);
+                continue;
+            }
+            peers.push(peer);
+        }
+        // Entries saved before addresses were checked may not parse
+        for (peer, _) in address_book.get_addresses(max_outbound) {
+            if peer.parse::<SocketAddr>().is_ok() && !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        let best_height = self.blockchain.get_best_height();
+        for peer in peers
+            .iter()
+            .filter(|peer| !peer.eq(&addr))
+            .take(max_outbound)
+        {
+            connect_to_peer(peer.as_str(), best_height);
         }
 
         for stream in listener.incoming() {
-            let blockchain = self.blockchain.clone();
-            thread::spawn(|| match stream {
-                Ok(stream) => {}
-                Err(e) => {}
-            });
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            if let Ok(peer_addr) = stream.peer_addr() {
+                if is_banned(peer_addr.ip()) {
+                    warn!("Refusing banned peer {}", peer_addr);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    continue;
+                }
+            }
+            let slot = match InboundSlot::acquire(GLOBAL_CONFIG.get_max_inbound()) {
+                Some(slot) => slot,
+                None => {
+                    warn!(
+                        "Refusing {:?}: inbound connection limit reached",
+                        stream.peer_addr()
+                    );
+                    let _ = stream.shutdown(Shutdown::Both);
+                    continue;
+                }
+            };
+            // Lifted once the peer's first package arrives
+            let timeout = Duration::from_millis(HANDSHAKE_TIMEOUT);
+            let result = stream
+                .set_read_timeout(Some(timeout))
+                .and_then(|_| start_session(self.blockchain.clone(), stream, Some(slot), None));
+            if let Err(e) = result {
+                error!("Connection failed: {}", e);
+            }
         }
     }
 }
@@ -77,7 +201,12 @@ pub enum Package {
         addr_from: String,
         block: Vec<u8>,
     },
-    GetBlocks {
+    Addr {
+        addr_from: String,
+        /// Peer addresses with when each was last seen
+        addrs: Vec<(String, i64)>,
+    },
+    GetAddr {
         addr_from: String,
     },
     GetData {
@@ -85,6 +214,22 @@ pub enum Package {
         op_type: OpType,
         id: Vec<u8>,
     },
+    GetHeaders {
+        addr_from: String,
+        locator: Vec<Vec<u8>>,
+    },
+    Headers {
+        addr_from: String,
+        headers: Vec<Vec<u8>>,
+    },
+    Ping {
+        addr_from: String,
+        nonce: u64,
+    },
+    Pong {
+        addr_from: String,
+        nonce: u64,
+    },
     Inv {
         addr_from: String,
         op_type: OpType,
@@ -101,13 +246,30 @@ pub enum Package {
     },
 }
 
+impl Package {
+    /// Listening address of the node that sent the package
+    pub fn get_addr_from(&self) -> &str {
+        match self {
+            Package::Addr { addr_from, .. }
+            | Package::Block { addr_from, .. }
+            | Package::GetAddr { addr_from, .. }
+            | Package::GetData { addr_from, .. }
+            | Package::GetHeaders { addr_from, .. }
+            | Package::Headers { addr_from, .. }
+            | Package::Inv { addr_from, .. }
+            | Package::Ping { addr_from, .. }
+            | Package::Pong { addr_from, .. }
+            | Package::Tx { addr_from, .. }
+            | Package::Version { addr_from, .. } => addr_from.as_str(),
+        }
+    }
+}
+
 fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
     send_data(
-        socket_addr,
+        addr,
         Package::GetData {
-            addr_from: node_addr,
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
             op_type,
             id: id.to_vec(),
         },
@@ -116,12 +278,10 @@ fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) {
 
 /// Sending inventory information to the specified address
 fn send_inv(addr: &str, op_type: OpType, blocks: &[Vec<u8>]) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
     send_data(
-        socket_addr,
+        addr,
         Package::Inv {
-            addr_from: node_addr,
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
             op_type,
             items: blocks.to_vec(),
         },
@@ -129,114 +289,835 @@ fn send_inv(addr: &str, op_type: OpType, blocks: &[Vec<u8>]) {
 }
 
 fn send_block(addr: &str, block: &Block) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
     send_data(
-        socket_addr,
+        addr,
         Package::Block {
-            addr_from: node_addr,
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
             block: block.serialize(),
         },
     )
 }
 
 pub fn send_tx(addr: &str, tx: &Transaction) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
     send_data(
-        socket_addr,
+        addr,
         Package::Tx {
-            addr_from: node_addr,
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
             transaction: tx.serialize(),
         },
     );
 }
 
 fn send_version(addr: &str, height: usize) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
     send_data(
-        socket_addr,
+        addr,
         Package::Version {
-            addr_from: node_addr,
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
             version: NODE_VERSION,
             best_height: height,
         },
     )
 }
 
-fn send_get_blocks(addr: &str) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+fn send_get_addr(addr: &str) {
+    GLOBAL_NODES.request_addr(addr);
+    send_data(
+        addr,
+        Package::GetAddr {
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
+        },
+    )
+}
+
+fn send_addr(addr: &str, addrs: Vec<(String, i64)>) {
+    send_data(
+        addr,
+        Package::Addr {
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
+            addrs,
+        },
+    )
+}
+
+fn send_get_headers(addr: &str, locator: Vec<Vec<u8>>) {
+    send_data(
+        addr,
+        Package::GetHeaders {
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
+            locator,
+        },
+    )
+}
+
+fn send_headers(addr: &str, headers: &[BlockHeader]) {
+    send_data(
+        addr,
+        Package::Headers {
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
+            headers: headers.iter().map(|header| header.serialize()).collect(),
+        },
+    )
+}
+
+fn send_ping(addr: &str, nonce: u64) {
+    send_data(
+        addr,
+        Package::Ping {
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
+            nonce,
+        },
+    )
+}
+
+fn send_pong(addr: &str, nonce: u64) {
     send_data(
-        socket_addr,
-        Package::GetBlocks {
-            addr_from: node_addr,
+        addr,
+        Package::Pong {
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
+            nonce,
         },
     )
 }
 
-fn send_data(addr: SocketAddr, pkg: Package) {
+/// Introduces ourselves to a peer and asks it for more addresses
+fn connect_to_peer(addr: &str, best_height: usize) {
+    if is_banned_addr(addr) {
+        return;
+    }
+    GLOBAL_NODES.add_node(addr.to_string());
+    send_version(addr, best_height);
+    send_get_addr(addr);
+}
+
+/// Notes in the address book that `addr` was just heard from
+fn record_seen(blockchain: &Blockchain, addr: &str) {
+    if addr.eq(GLOBAL_CONFIG.get_node_addr().as_str()) {
+        return;
+    }
+    let address_book = AddressBook::new(blockchain.get_db().clone());
+    address_book.add(addr, crate::current_timestamp());
+}
+
+/// Loopback peers are never banned, as in the README's setup several local nodes
+/// share 127.0.0.1 and one bad node would cut off the rest
+fn is_banned(ip: IpAddr) -> bool {
+    !ip.is_loopback()
+        && GLOBAL_BLOCKCHAIN
+            .get()
+            .is_some_and(|blockchain| BanList::new(blockchain.get_db().clone()).is_banned(ip))
+}
+
+/// Whether the IP of the listening address `addr` is banned
+fn is_banned_addr(addr: &str) -> bool {
+    addr.parse::<SocketAddr>()
+        .is_ok_and(|addr| is_banned(addr.ip()))
+}
+
+/// Adds `points` to the ban score of `ip`, banning it once the score reaches
+/// `BAN_THRESHOLD`. Scores are kept per IP rather than per `addr_from`, which the
+/// peer chooses. Loopback peers are only logged, see `is_banned`.
+fn misbehaving(blockchain: &Blockchain, ip: IpAddr, points: u32, reason: &str) {
+    if points == 0 {
+        return;
+    }
+    if ip.is_loopback() {
+        warn!(
+            "Peer {} misbehaved ({}), not scored on loopback",
+            ip, reason
+        );
+        return;
+    }
+    let score = GLOBAL_NODES.add_ban_score(ip, points);
+    warn!("Peer {} misbehaved ({}), ban score {}", ip, reason, score);
+    if score >= BAN_THRESHOLD {
+        ban_peer(
+            blockchain,
+            ip,
+            crate::current_timestamp() + DEFAULT_BAN_DURATION,
+        );
+    }
+}
+
+/// Snapshot of every known peer with the ban score of its IP, for the admin API
+pub fn get_peers() -> Vec<(Node, u32)> {
+    GLOBAL_NODES
+        .get_nodes()
+        .into_iter()
+        .map(|node| {
+            let ban_score = node.get_ip().map_or(0, |ip| GLOBAL_NODES.get_ban_score(ip));
+            (node, ban_score)
+        })
+        .collect()
+}
+
+/// Bans `ip` until `until`, closing its sessions and forgetting their nodes
+pub fn ban_peer(blockchain: &Blockchain, ip: IpAddr, until: i64) {
+    BanList::new(blockchain.get_db().clone()).ban(ip, until);
+    for (addr, connection) in GLOBAL_NODES.get_connections_from(ip) {
+        connection.close();
+        GLOBAL_NODES.evict_node(addr.as_str());
+    }
+    GLOBAL_NODES.clear_ban_score(ip);
+    warn!("Banned {} until {}", ip, until);
+}
+
+/// Points a peer earns for sending a block or header that failed validation.
+/// Orphans and blocks from the near future can come from honest peers.
+fn block_ban_score(e: &BlockValidationError) -> u32 {
+    match e {
+        BlockValidationError::AlreadyKnown
+        | BlockValidationError::UnknownParent(_)
+        | BlockValidationError::TimestampTooNew { .. } => 0,
+        _ => INVALID_BLOCK_SCORE,
+    }
+}
+
+/// Queues a package on the peer's session, opening one first if there is none. When
+/// no node is running, as for the `send` command, the package is written on a
+/// one-off connection instead.
+fn send_data(addr: &str, pkg: Package) {
+    let addr: SocketAddr = match addr.parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            error!("Not sending to {:?}: invalid address", addr);
+            return;
+        }
+    };
     info!("send package: {:?}", pkg);
-    let stream = TcpStream::connect(addr);
+    let frame = match codec::encode(&pkg) {
+        Ok(frame) => frame,
+        Err(e) => {
+            error!("Failed to encode package for {}: {}", addr, e);
+            return;
+        }
+    };
+    let peer = addr.to_string();
+    if let Some(connection) = GLOBAL_NODES.get_connection(peer.as_str()) {
+        if connection.send(frame.clone()) {
+            return;
+        }
+        GLOBAL_NODES.disconnect(peer.as_str(), connection.get_id());
+    }
+
+    let blockchain = GLOBAL_BLOCKCHAIN.get();
+    if is_banned(addr.ip()) {
+        warn!("Dropping package for {}: peer is banned", addr);
+        return;
+    }
+    if blockchain.is_some()
+        && GLOBAL_NODES.count_connections(Direction::Outbound) >= GLOBAL_CONFIG.get_max_outbound()
+    {
+        warn!(
+            "Dropping package for {}: outbound connection limit reached",
+            addr
+        );
+        return;
+    }
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_millis(TCP_CONNECT_TIMEOUT));
     if stream.is_err() {
         error!("The {} is not valid", addr);
-        GLOBAL_NODES.evict_node(addr.to_string().as_str());
+        GLOBAL_NODES.evict_node(peer.as_str());
         return;
     }
     let mut stream = stream.unwrap();
     let _ = stream.set_write_timeout(Option::from(Duration::from_millis(TCP_WRITE_TIMEOUT)));
-    let _ = serde_json::to_writer(&stream, &pkg);
-    let _ = stream.flush();
+    match blockchain {
+        Some(blockchain) => match start_session(blockchain.clone(), stream, None, Some(&peer)) {
+            Ok(connection) => {
+                connection.send(frame);
+            }
+            Err(e) => error!("Connection to {} failed: {}", addr, e),
+        },
+        None => {
+            if let Err(e) = codec::write_message(&mut stream, &pkg) {
+                error!("Failed to send to {}: {}", addr, e);
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
 }
 
-fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let peer_addr = stream.peer_addr()?;
-    let reader = BufReader::new(&stream);
-    let pkg_reader = Deserializer::from_reader(reader).into_iter::<Package>();
-    for pkg in pkg_reader {
-        let pkg = pkg?;
-        info!("Receive request from {}: {:?}", peer_addr, pkg);
-        match pkg {
-            Package::Block { addr_from, block } => {
-                let block = Block::deserialize(block.as_slice());
-                blockchain.add_block(&block);
+/// Starts the reader and writer threads of a peer session. Inbound sessions hold
+/// their `slot` until the reader stops. Outbound sessions are registered under the
+/// address we dialed; inbound ones once the peer names its address in its first
+/// package.
+fn start_session(
+    blockchain: Blockchain,
+    stream: TcpStream,
+    slot: Option<InboundSlot>,
+    peer: Option<&str>,
+) -> io::Result<Connection> {
+    let direction = match slot {
+        Some(_) => Direction::Inbound,
+        None => Direction::Outbound,
+    };
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+    let connection = Connection::new(
+        direction,
+        stream.peer_addr()?.ip(),
+        sender,
+        stream.try_clone()?,
+    );
+    let mut write_stream = stream.try_clone()?;
+    write_stream.set_write_timeout(Some(Duration::from_millis(TCP_WRITE_TIMEOUT)))?;
+    if let Some(peer) = peer {
+        GLOBAL_NODES.connect(peer, connection.clone());
+    }
+
+    // Runs until every handle on the queue is dropped or the socket fails
+    thread::spawn(move || {
+        for frame in receiver {
+            if let Err(e) = write_stream.write_all(frame.as_slice()) {
+                error!("Failed to write to {:?}: {}", write_stream.peer_addr(), e);
+                break;
+            }
+        }
+        let _ = write_stream.shutdown(Shutdown::Both);
+    });
+
+    let session = connection.clone();
+    thread::spawn(move || {
+        if let Err(e) = serve(blockchain, stream, session) {
+            error!("Error on serving client request: {}", e);
+        }
+        drop(slot);
+    });
+    Ok(connection)
+}
+
+/// Mines every pooled transaction plus a coinbase paying the configured mining
+/// address, then announces the new block to all known nodes. Pooled transactions
+/// that no longer check out against the chain are evicted rather than mined, since
+/// any block holding them would be refused.
+fn mine_memory_pool(blockchain: &Blockchain) {
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    let mut txs = vec![];
+    for tx in GLOBAL_MEMORY_POOL.get_all() {
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        if !spends_unspent(&utxo_set, &tx) {
+            warn!("Evicting transaction {}: its inputs are spent", txid_hex);
+            GLOBAL_MEMORY_POOL.remove(&tx);
+        } else if let Err(e) = blockchain.validate_transaction(&tx) {
+            warn!("Evicting transaction {}: {}", txid_hex, e);
+            GLOBAL_MEMORY_POOL.remove(&tx);
+        } else {
+            txs.push(tx);
+        }
+    }
+    if txs.is_empty() {
+        return;
+    }
+    let mining_address = GLOBAL_CONFIG.get_mining_addr().unwrap();
+    txs.push(Transaction::new_coinbase_tx(mining_address.as_str()));
+
+    let new_block = match blockchain.mine_block(&txs) {
+        Ok(block) => block,
+        Err(e) => {
+            error!("Failed to mine the memory pool: {}", e);
+            return;
+        }
+    };
+    info!("New block {} is mined!", new_block.get_hash());
+
+    for tx in &txs {
+        GLOBAL_MEMORY_POOL.remove_confirmed(tx);
+    }
+
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+    for node in GLOBAL_NODES.get_nodes() {
+        if node_addr.eq(node.get_addr().as_str()) {
+            continue;
+        }
+        send_inv(
+            node.get_addr().as_str(),
+            OpType::Block,
+            &[new_block.get_hash_bytes()],
+        );
+    }
+}
+
+/// Drops sessions with peers that stopped responding, then pings the rest
+fn keep_alive() {
+    for (addr, connection) in
+        GLOBAL_NODES.get_stale_connections(Duration::from_millis(PEER_TIMEOUT))
+    {
+        warn!("Disconnecting {}: not responding", addr);
+        connection.close();
+        GLOBAL_NODES.disconnect(addr.as_str(), connection.get_id());
+    }
+    for node in GLOBAL_NODES.get_nodes() {
+        if node.get_connection().is_none() {
+            continue;
+        }
+        let nonce = utils::new_nonce();
+        if GLOBAL_NODES.start_ping(node.get_addr().as_str(), nonce) {
+            send_ping(node.get_addr().as_str(), nonce);
+        }
+    }
+}
+
+/// Spreads the queued block downloads over every peer known to have them, so bodies
+/// come in from several peers at once. The fastest peers are served first.
+fn request_blocks() {
+    GLOBAL_BLOCKS_IN_TRANSIT.expire(Duration::from_millis(BLOCK_DOWNLOAD_TIMEOUT));
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+    for node in GLOBAL_NODES.get_nodes_by_latency() {
+        if node_addr.eq(node.get_addr().as_str()) {
+            continue;
+        }
+        let block_hashes = GLOBAL_BLOCKS_IN_TRANSIT.assign(
+            node.get_addr().as_str(),
+            node.get_best_height(),
+            MAX_BLOCKS_IN_FLIGHT_PER_PEER,
+        );
+        for block_hash in block_hashes {
+            send_get_data(node.get_addr().as_str(), OpType::Block, &block_hash);
+        }
+    }
+}
+
+/// Whether every input of `tx` spends an output that is unspent on the best chain
+fn spends_unspent(utxo_set: &UTXOSet, tx: &Transaction) -> bool {
+    tx.get_vin()
+        .iter()
+        .all(|vin| utxo_set.get_utxo(vin.get_txid(), vin.get_vout()).is_some())
+}
+
+/// Validates and connects a downloaded block, followed by any blocks that were
+/// waiting for it. Bodies arrive out of order from several peers, so a block whose
+/// parent header is known but whose parent body is not is held back until it is.
+/// Only the block `addr_from`, at `peer_ip`, sent counts against it if invalid, not
+/// the orphans other peers sent.
+fn process_block(blockchain: &Blockchain, block: Block, addr_from: &str, peer_ip: IpAddr) {
+    let received_hash = block.get_hash().to_string();
+    let mut pending = vec![block];
+    while let Some(block) = pending.pop() {
+        match blockchain.validate_block(&block) {
+            Ok(()) => {
+                let update = match blockchain.add_block(&block) {
+                    Ok(update) => update,
+                    Err(e) => {
+                        error!("Failed to connect block {}: {}", block.get_hash(), e);
+                        continue;
+                    }
+                };
                 info!("Added block {}", block.get_hash());
+                for connected in update.get_connected() {
+                    for tx in connected.get_transactions() {
+                        GLOBAL_MEMORY_POOL.remove_confirmed(tx);
+                    }
+                }
+                // Transactions from abandoned blocks go back into the pool unless the
+                // new branch spent their inputs. The pool only takes transactions whose
+                // inputs are on the best chain, so one spending the output of another
+                // abandoned transaction is dropped and has to be broadcast again.
+                let utxo_set = UTXOSet::new(blockchain.clone());
+                for disconnected in update.get_disconnected() {
+                    for tx in disconnected.get_transactions() {
+                        if spends_unspent(&utxo_set, tx)
+                            && blockchain.validate_transaction(tx).is_ok()
+                        {
+                            GLOBAL_MEMORY_POOL.add(tx.clone());
+                        }
+                    }
+                }
+                pending.extend(GLOBAL_ORPHAN_BLOCKS.take_children(block.get_hash()));
+            }
+            Err(BlockValidationError::AlreadyKnown) => {}
+            Err(BlockValidationError::UnknownParent(parent_hash))
+                if blockchain
+                    .get_block_header(parent_hash.as_bytes())
+                    .is_some() =>
+            {
+                GLOBAL_ORPHAN_BLOCKS.add(block);
+                // The parent may have been connected on another connection meanwhile
+                if blockchain.has_block(parent_hash.as_bytes()) {
+                    pending.extend(GLOBAL_ORPHAN_BLOCKS.take_children(parent_hash.as_str()));
+                }
+            }
+            Err(BlockValidationError::UnknownParent(_)) => {
+                send_get_headers(addr_from, blockchain.get_block_locator());
+            }
+            Err(e) => {
+                error!(
+                    "Rejected block {} from {}: {}",
+                    block.get_hash(),
+                    addr_from,
+                    e
+                );
+                if block.get_hash() == received_hash {
+                    misbehaving(blockchain, peer_ip, block_ban_score(&e), "invalid block");
+                }
+            }
+        }
+    }
+}
 
-                if GLOBAL_BLOCKS_IN_TRANSIT.len() > 0 {
-                    let block_hash = GLOBAL_BLOCKS_IN_TRANSIT.first().unwrap();
-                    send_get_data(addr_from.as_str(), OpType::Block, &block_hash);
+/// Read loop of a peer session. Returns when the peer hangs up or sends something
+/// undecodable, closing the session.
+fn serve(
+    blockchain: Blockchain,
+    stream: TcpStream,
+    connection: Connection,
+) -> Result<(), Box<dyn Error>> {
+    let peer_addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(&stream);
+    let mut peer = None;
+    let result = read_packages(blockchain.clone(), &mut reader, &connection, &mut peer);
+    if let Some(peer) = peer {
+        GLOBAL_NODES.disconnect(peer.as_str(), connection.get_id());
+        record_seen(&blockchain, peer.as_str());
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    info!("Connection with {} closed", peer_addr);
+    result
+}
 
-                    GLOBAL_BLOCKS_IN_TRANSIT.remove(&block_hash);
-                } else {
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
+/// Handles packages until the stream ends. The session is registered under the
+/// sender's address, stored in `peer`, as soon as the first one arrives.
+fn read_packages(
+    blockchain: Blockchain,
+    reader: &mut BufReader<&TcpStream>,
+    connection: &Connection,
+    peer: &mut Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    // Scores go to the IP the session connects from
+    let peer_ip = connection.get_ip();
+    loop {
+        let pkg = match codec::read_message(reader) {
+            Ok(Some(pkg)) => pkg,
+            Ok(None) => return Ok(()),
+            Err(codec::CodecError::Io(e)) => return Err(e.into()),
+            Err(e) => {
+                // The stream can't be resynchronised, so the session ends either way
+                misbehaving(
+                    &blockchain,
+                    peer_ip,
+                    MALFORMED_MESSAGE_SCORE,
+                    &e.to_string(),
+                );
+                return Err(e.into());
+            }
+        };
+        info!("Receive request from {}: {:?}", pkg.get_addr_from(), pkg);
+        // Replies go to `addr_from`, so a package without a valid one can't be handled
+        if pkg.get_addr_from().parse::<SocketAddr>().is_err() {
+            misbehaving(
+                &blockchain,
+                peer_ip,
+                MALFORMED_MESSAGE_SCORE,
+                "bad addr_from",
+            );
+            if peer.is_none() {
+                warn!(
+                    "Dropping session: {:?} is not a peer address",
+                    pkg.get_addr_from()
+                );
+                return Ok(());
+            }
+            continue;
+        }
+        if peer.is_none() {
+            let addr_from = pkg.get_addr_from().to_string();
+            // Banned while the session was waiting for its first package
+            if is_banned(peer_ip) {
+                warn!("Refusing banned peer {}", peer_ip);
+                return Ok(());
+            }
+            if !GLOBAL_NODES.connect(addr_from.as_str(), connection.clone()) {
+                warn!("Dropping session: {} is already connected", addr_from);
+                return Ok(());
+            }
+            record_seen(&blockchain, addr_from.as_str());
+            reader.get_ref().set_read_timeout(None)?;
+            *peer = Some(addr_from);
+        }
+        let session_peer = peer.clone().unwrap();
+        // Everything is answered and attributed per session, so a package naming
+        // another sender is refused rather than redirected
+        if pkg.get_addr_from() != session_peer {
+            warn!(
+                "Dropping package from {}: it claims to be from {}",
+                session_peer,
+                pkg.get_addr_from()
+            );
+            misbehaving(
+                &blockchain,
+                peer_ip,
+                MALFORMED_MESSAGE_SCORE,
+                "addr_from changed",
+            );
+            continue;
+        }
+        GLOBAL_NODES.touch(session_peer.as_str());
+        match pkg {
+            Package::Addr { addrs, .. } => {
+                // Only answers to our own GetAddr are taken, so a peer can't keep
+                // filling the address book
+                if !GLOBAL_NODES.complete_addr_request(session_peer.as_str()) {
+                    misbehaving(&blockchain, peer_ip, SPAM_SCORE, "unsolicited addr");
+                    continue;
+                }
+                if addrs.len() > MAX_ADDR_RESULTS {
+                    misbehaving(&blockchain, peer_ip, SPAM_SCORE, "oversized addr");
+                }
+                let address_book = AddressBook::new(blockchain.get_db().clone());
+                let node_addr = GLOBAL_CONFIG.get_node_addr();
+                let now = crate::current_timestamp();
+                let mut new_peers = vec![];
+                for (addr, last_seen) in addrs.into_iter().take(MAX_ADDR_RESULTS) {
+                    if addr.eq(&node_addr) || addr.parse::<SocketAddr>().is_err() {
+                        continue;
+                    }
+                    if !address_book.contains(addr.as_str()) {
+                        new_peers.push(addr.clone());
+                    }
+                    // A peer can't have seen anyone later than now
+                    address_book.add(addr.as_str(), last_seen.min(now));
+                }
+                info!(
+                    "Learned {} new addresses from {}",
+                    new_peers.len(),
+                    session_peer
+                );
+
+                let free_slots = GLOBAL_CONFIG
+                    .get_max_outbound()
+                    .saturating_sub(GLOBAL_NODES.count_connections(Direction::Outbound));
+                let best_height = blockchain.get_best_height();
+                for peer in new_peers.into_iter().take(free_slots) {
+                    if GLOBAL_NODES.get_connection(peer.as_str()).is_none() {
+                        connect_to_peer(peer.as_str(), best_height);
+                    }
                 }
             }
-            Package::GetBlocks { addr_from } => {
-                let blocks = blockchain.get_block_hashes();
-                send_inv(&addr_from.as_str(), OpType::Block, &blocks);
+            Package::GetAddr { .. } => {
+                let address_book = AddressBook::new(blockchain.get_db().clone());
+                let addrs = address_book
+                    .get_addresses(MAX_ADDR_RESULTS + 1)
+                    .into_iter()
+                    .filter(|(addr, _)| !addr.eq(&session_peer) && !is_banned_addr(addr))
+                    .take(MAX_ADDR_RESULTS)
+                    .collect();
+                send_addr(session_peer.as_str(), addrs);
             }
-            Package::GetData {
-                addr_from,
-                op_type,
-                id,
-            } => match op_type {
+            Package::Block { block, .. } => {
+                let block = match Block::deserialize(block.as_slice()) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        error!("Undecodable block from {}: {}", session_peer, e);
+                        misbehaving(&blockchain, peer_ip, MALFORMED_MESSAGE_SCORE, "bad block");
+                        continue;
+                    }
+                };
+                GLOBAL_BLOCKS_IN_TRANSIT.remove(block.get_hash().as_bytes());
+                process_block(&blockchain, block, session_peer.as_str(), peer_ip);
+                request_blocks();
+            }
+            Package::GetHeaders { locator, .. } => {
+                if locator.len() > MAX_LOCATOR_SIZE {
+                    misbehaving(&blockchain, peer_ip, SPAM_SCORE, "oversized locator");
+                    continue;
+                }
+                let headers = blockchain.get_headers_after(&locator, MAX_HEADERS_RESULTS);
+                if !headers.is_empty() {
+                    send_headers(session_peer.as_str(), &headers);
+                }
+            }
+            Package::Headers { headers, .. } => {
+                if headers.len() > MAX_HEADERS_RESULTS {
+                    misbehaving(&blockchain, peer_ip, SPAM_SCORE, "oversized headers");
+                    continue;
+                }
+                // Every header is checked and stored before any body is requested
+                let mut downloads = vec![];
+                let mut accepted = 0;
+                let mut last_accepted = None;
+                for header_bytes in &headers {
+                    let header = match BlockHeader::deserialize(header_bytes.as_slice()) {
+                        Ok(header) => header,
+                        Err(e) => {
+                            error!("Undecodable header from {}: {}", session_peer, e);
+                            misbehaving(
+                                &blockchain,
+                                peer_ip,
+                                MALFORMED_MESSAGE_SCORE,
+                                "bad header",
+                            );
+                            break;
+                        }
+                    };
+                    let block_hash = header.hash();
+                    if blockchain.get_block_header(block_hash.as_bytes()).is_none() {
+                        if let Err(e) = blockchain.validate_header(&header) {
+                            error!(
+                                "Rejected header {} from {}: {}",
+                                block_hash, session_peer, e
+                            );
+                            misbehaving(
+                                &blockchain,
+                                peer_ip,
+                                block_ban_score(&e),
+                                "invalid header",
+                            );
+                            break;
+                        }
+                        blockchain.add_header(&header);
+                    }
+                    if !blockchain.has_block(block_hash.as_bytes()) {
+                        downloads.push((block_hash.as_bytes().to_vec(), header.get_height()));
+                    }
+                    accepted += 1;
+                    last_accepted = Some((block_hash, header.get_height()));
+                }
+                if let Some((last_hash, last_height)) = last_accepted {
+                    GLOBAL_NODES.update_best_height(session_peer.as_str(), last_height);
+                    // A full batch means the peer probably has more to send
+                    if accepted == MAX_HEADERS_RESULTS {
+                        let mut locator = vec![last_hash.into_bytes()];
+                        locator.extend(blockchain.get_block_locator());
+                        send_get_headers(session_peer.as_str(), locator);
+                    }
+                }
+                GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(downloads);
+                request_blocks();
+            }
+            Package::GetData { op_type, id, .. } => match op_type {
                 OpType::Block => {
                     if let Some(block) = blockchain.get_block(id.as_slice()) {
-                        send_block(addr_from.as_str(), &block);
+                        send_block(session_peer.as_str(), &block);
                     }
                 }
                 OpType::Tx => {
                     let txid_hex = HEXLOWER.encode(id.as_slice());
                     if let Some(tx) = GLOBAL_MEMORY_POOL.get(txid_hex.as_str()) {
-                        send_tx(addr_from.as_str(), &tx);
+                        send_tx(session_peer.as_str(), &tx);
+                    }
+                }
+            },
+            Package::Inv { items, .. } if items.len() > MAX_INV_ITEMS => {
+                misbehaving(&blockchain, peer_ip, SPAM_SCORE, "oversized inv");
+            }
+            Package::Inv { op_type, items, .. } => match op_type {
+                OpType::Block => {
+                    // Unknown blocks are fetched headers-first
+                    let has_unknown = items
+                        .iter()
+                        .any(|block_hash| blockchain.get_block_header(block_hash).is_none());
+                    if has_unknown {
+                        send_get_headers(session_peer.as_str(), blockchain.get_block_locator());
+                    }
+                }
+                OpType::Tx => {
+                    if let Some(txid) = items.first() {
+                        let txid_hex = HEXLOWER.encode(txid);
+                        if !GLOBAL_MEMORY_POOL.contains(txid_hex.as_str()) {
+                            send_get_data(session_peer.as_str(), OpType::Tx, txid);
+                        }
                     }
                 }
             },
+            Package::Ping { nonce, .. } => send_pong(session_peer.as_str(), nonce),
+            Package::Pong { nonce, .. } => {
+                if let Some(rtt) = GLOBAL_NODES.complete_ping(session_peer.as_str(), nonce) {
+                    info!("Round trip to {} took {:?}", session_peer, rtt);
+                }
+            }
+            Package::Tx { transaction, .. } => {
+                let tx = match Transaction::deserialize(transaction.as_slice()) {
+                    Ok(tx) => tx,
+                    Err(e) => {
+                        error!("Undecodable transaction from {}: {}", session_peer, e);
+                        misbehaving(
+                            &blockchain,
+                            peer_ip,
+                            MALFORMED_MESSAGE_SCORE,
+                            "bad transaction",
+                        );
+                        continue;
+                    }
+                };
+                let txid = tx.get_id().to_vec();
+                let txid_hex = HEXLOWER.encode(txid.as_slice());
+                if GLOBAL_MEMORY_POOL.contains(txid_hex.as_str()) {
+                    continue;
+                }
+                // Unknown or spent inputs may just be a race with a block, so only a
+                // transaction that is invalid in itself counts against the peer
+                match blockchain.validate_transaction(&tx) {
+                    Ok(()) => {}
+                    Err(e @ BlockValidationError::MissingInput { .. }) => {
+                        error!(
+                            "Rejected transaction {} from {}: {}",
+                            txid_hex, session_peer, e
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Rejected transaction {} from {}: {}",
+                            txid_hex, session_peer, e
+                        );
+                        misbehaving(
+                            &blockchain,
+                            peer_ip,
+                            INVALID_TX_SCORE,
+                            "invalid transaction",
+                        );
+                        continue;
+                    }
+                }
+                let utxo_set = UTXOSet::new(blockchain.clone());
+                if !spends_unspent(&utxo_set, &tx) {
+                    error!(
+                        "Rejected transaction {} from {}: inputs are spent",
+                        txid_hex, session_peer
+                    );
+                    continue;
+                }
+                if !GLOBAL_MEMORY_POOL.add(tx) {
+                    error!(
+                        "Rejected transaction {} from {}: conflicts with the memory pool",
+                        txid_hex, session_peer
+                    );
+                    continue;
+                }
+
+                // Relay the new transaction to everyone except ourselves and the sender
+                let node_addr = GLOBAL_CONFIG.get_node_addr();
+                for node in GLOBAL_NODES.get_nodes() {
+                    if node_addr.eq(node.get_addr().as_str()) {
+                        continue;
+                    }
+                    if session_peer.eq(node.get_addr().as_str()) {
+                        continue;
+                    }
+                    send_inv(
+                        node.get_addr().as_str(),
+                        OpType::Tx,
+                        std::slice::from_ref(&txid),
+                    );
+                }
+
+                if GLOBAL_MEMORY_POOL.len() >= TRANSACTION_THRESHOLD && GLOBAL_CONFIG.is_miner() {
+                    mine_memory_pool(&blockchain);
+                }
+            }
+            Package::Version {
+                version,
+                best_height,
+                ..
+            } => {
+                info!("version = {}, best_height = {}", version, best_height);
+                GLOBAL_NODES.update_best_height(session_peer.as_str(), best_height);
+                let local_best_height = blockchain.get_best_height();
+                if local_best_height < best_height {
+                    send_get_headers(session_peer.as_str(), blockchain.get_block_locator());
+                }
+                if local_best_height > best_height {
+                    send_version(session_peer.as_str(), local_best_height);
+                }
+            }
         }
     }
-
-    let _ = stream.shutdown(Shutdown::Both);
-    Ok(())
 }
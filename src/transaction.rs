@@ -1,13 +1,18 @@
+use std::collections::HashMap;
+
+use data_encoding::HEXLOWER;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
     blockchain::Blockchain,
     utils::{self, base58_decode},
+    utxo_set::UTXOSet,
     wallet,
+    wallets::Wallets,
 };
 
-const SUBSIDY: i32 = 10;
+pub const SUBSIDY: i32 = 10;
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Transaction {
@@ -23,8 +28,10 @@ impl Transaction {
 
     pub fn new_coinbase_tx(to: &str) -> Transaction {
         let txout = TXOutput::new(SUBSIDY, to);
-        let mut tx_input = TXInput::default();
-        tx_input.signature = Uuid::new_v4().as_bytes().to_vec();
+        let tx_input = TXInput {
+            signature: Uuid::new_v4().as_bytes().to_vec(),
+            ..Default::default()
+        };
 
         let mut tx = Transaction {
             id: Vec::new(),
@@ -35,6 +42,49 @@ impl Transaction {
         tx
     }
 
+    /// Builds a payment from the `from` wallet, spending just enough of its unspent
+    /// outputs to cover `amount` and returning any change back to `from`.
+    pub fn new_utxo_transaction(
+        from: &str,
+        to: &str,
+        amount: i32,
+        utxo_set: &UTXOSet,
+    ) -> Transaction {
+        let wallets = Wallets::new();
+        let wallet = wallets.get_wallet(from).expect("unable to find wallet");
+        let public_key_hash = wallet::hash_pub_key(wallet.get_public_key());
+
+        let (accumulated, valid_outputs) =
+            utxo_set.find_spendable_outputs(public_key_hash.as_slice(), amount);
+        if accumulated < amount {
+            panic!("Error: Not enough funds");
+        }
+
+        let mut inputs = vec![];
+        for (txid_hex, outs) in valid_outputs {
+            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
+            for out in outs {
+                let mut input = TXInput::new(txid.as_slice(), out);
+                input.pub_key = wallet.get_public_key().to_vec();
+                inputs.push(input);
+            }
+        }
+
+        let mut outputs = vec![TXOutput::new(amount, to)];
+        if accumulated > amount {
+            outputs.push(TXOutput::new(accumulated - amount, from));
+        }
+
+        let mut tx = Transaction {
+            id: Vec::new(),
+            vin: inputs,
+            vout: outputs,
+        };
+        tx.id = tx.hash();
+        tx.sign(utxo_set.get_blockchain(), wallet.get_pkcs8());
+        tx
+    }
+
     fn hash(&mut self) -> Vec<u8> {
         let tx_copy = Transaction {
             id: Vec::new(),
@@ -48,17 +98,64 @@ impl Transaction {
         bincode::serialize(self).unwrap().to_vec()
     }
 
-    pub fn verify(&self, blockchain: &Blockchain) -> bool {
+    pub fn deserialize(bytes: &[u8]) -> Result<Transaction, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+
+    /// Signs every input with the key in `pkcs8`. Each input signs a trimmed copy of
+    /// the transaction in which only that input carries the referenced output's
+    /// `pub_key_hash`, mirroring what `verify_with_prev_txs` reconstructs.
+    pub fn sign(&mut self, blockchain: &Blockchain, pkcs8: &[u8]) {
         if self.is_coinbase() {
-            return true;
+            return;
         }
+        let prev_txs = self.find_prev_transactions(blockchain);
+        self.sign_with_prev_txs(&prev_txs, pkcs8);
+    }
+
+    fn find_prev_transactions(&self, blockchain: &Blockchain) -> HashMap<String, Transaction> {
+        let mut prev_txs = HashMap::new();
+        for vin in &self.vin {
+            // Unknown inputs are left out, and signing them fails
+            if let Some(prev_tx) = blockchain.find_transaction(vin.get_txid()) {
+                prev_txs.insert(HEXLOWER.encode(prev_tx.get_id()), prev_tx);
+            }
+        }
+        prev_txs
+    }
+
+    fn sign_with_prev_txs(&mut self, prev_txs: &HashMap<String, Transaction>, pkcs8: &[u8]) {
+        let mut tx_copy = self.trimmed_copy();
+        for idx in 0..self.vin.len() {
+            let txid_hex = HEXLOWER.encode(self.vin[idx].get_txid());
+            let prev_tx = prev_txs
+                .get(txid_hex.as_str())
+                .expect("ERROR: Previous transaction is not correct");
+            tx_copy.vin[idx].signature = Vec::new();
+            tx_copy.vin[idx].pub_key = prev_tx.vout[self.vin[idx].vout].pub_key_hash.clone();
+            tx_copy.id = tx_copy.hash();
+            tx_copy.vin[idx].pub_key = Vec::new();
+
+            let signature = utils::ecdsa_p256_sha256_sign_digest(pkcs8, tx_copy.get_id());
+            self.vin[idx].signature = signature;
+        }
+    }
+
+    pub fn verify_with_prev_txs(&self, prev_txs: &HashMap<String, Transaction>) -> bool {
         let mut tx_copy = self.trimmed_copy();
         for (idx, vin) in self.vin.iter().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct");
+            let txid_hex = HEXLOWER.encode(vin.get_txid());
+            let prev_tx = match prev_txs.get(txid_hex.as_str()) {
+                Some(prev_tx) => prev_tx,
+                None => return false,
+            };
+            if vin.vout >= prev_tx.vout.len() {
+                return false;
+            }
+            // The signature only proves ownership of the key the input names
+            if !vin.uses_key(prev_tx.vout[vin.vout].get_pub_key_hash()) {
+                return false;
             }
-            let prev_tx = prev_tx_option.unwrap();
             tx_copy.vin[idx].signature = Vec::new();
             tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
             tx_copy.id = tx_copy.hash();
@@ -137,10 +234,10 @@ impl TXInput {
         self.pub_key.as_slice()
     }
 
-    // pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
-    //     let locking_hash = wallet::hash_pub_key(&self.pub_key.as_slice());
-    //     locking_hash == pub_key_hash
-    // }
+    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
+        let locking_hash = wallet::hash_pub_key(self.pub_key.as_slice());
+        locking_hash.eq(pub_key_hash)
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -177,3 +274,135 @@ impl TXOutput {
         self.pub_key_hash.eq(pub_key_hash)
     }
 }
+
+#[cfg(test)]
+impl Transaction {
+    /// Spends output `vout` of `prev_tx`, owned by `from`, into `outputs`
+    pub fn new_test_spend(
+        prev_tx: &Transaction,
+        vout: usize,
+        from: &wallet::Wallet,
+        outputs: Vec<TXOutput>,
+    ) -> Transaction {
+        let mut input = TXInput::new(prev_tx.get_id(), vout);
+        input.pub_key = from.get_public_key().to_vec();
+        let mut tx = Transaction {
+            id: Vec::new(),
+            vin: vec![input],
+            vout: outputs,
+        };
+        tx.id = tx.hash();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(HEXLOWER.encode(prev_tx.get_id()), prev_tx.clone());
+        tx.sign_with_prev_txs(&prev_txs, from.get_pkcs8());
+        tx
+    }
+
+    /// An unsigned transaction with exactly the given inputs and outputs
+    pub fn new_test_unsigned(vin: Vec<TXInput>, outputs: Vec<TXOutput>) -> Transaction {
+        let mut tx = Transaction {
+            id: Vec::new(),
+            vin,
+            vout: outputs,
+        };
+        tx.id = tx.hash();
+        tx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::Wallet;
+
+    fn spend(prev_tx: &Transaction, from: &Wallet, to: &str, amount: i32) -> Transaction {
+        let mut input = TXInput::new(prev_tx.get_id(), 0);
+        input.pub_key = from.get_public_key().to_vec();
+        let mut tx = Transaction {
+            id: Vec::new(),
+            vin: vec![input],
+            vout: vec![TXOutput::new(amount, to)],
+        };
+        tx.id = tx.hash();
+        tx
+    }
+
+    fn prev_txs_of(tx: &Transaction) -> HashMap<String, Transaction> {
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(HEXLOWER.encode(tx.get_id()), tx.clone());
+        prev_txs
+    }
+
+    #[test]
+    fn signed_transaction_verifies() {
+        let sender = Wallet::new();
+        let receiver = Wallet::new();
+        let coinbase = Transaction::new_coinbase_tx(&sender.get_address());
+        let prev_txs = prev_txs_of(&coinbase);
+
+        let mut tx = spend(&coinbase, &sender, &receiver.get_address(), SUBSIDY);
+        tx.sign_with_prev_txs(&prev_txs, sender.get_pkcs8());
+        assert!(tx.verify_with_prev_txs(&prev_txs));
+    }
+
+    #[test]
+    fn tampered_output_fails_verification() {
+        let sender = Wallet::new();
+        let receiver = Wallet::new();
+        let coinbase = Transaction::new_coinbase_tx(&sender.get_address());
+        let prev_txs = prev_txs_of(&coinbase);
+
+        let mut tx = spend(&coinbase, &sender, &receiver.get_address(), SUBSIDY);
+        tx.sign_with_prev_txs(&prev_txs, sender.get_pkcs8());
+        tx.vout[0].value = SUBSIDY * 2;
+        assert!(!tx.verify_with_prev_txs(&prev_txs));
+
+        let mut tx = spend(&coinbase, &sender, &receiver.get_address(), SUBSIDY);
+        tx.sign_with_prev_txs(&prev_txs, sender.get_pkcs8());
+        tx.vout[0].pub_key_hash = wallet::hash_pub_key(sender.get_public_key());
+        assert!(!tx.verify_with_prev_txs(&prev_txs));
+    }
+
+    #[test]
+    fn foreign_key_fails_verification() {
+        let owner = Wallet::new();
+        let thief = Wallet::new();
+        let coinbase = Transaction::new_coinbase_tx(&owner.get_address());
+        let prev_txs = prev_txs_of(&coinbase);
+
+        // The thief claims the owner's public key but can only sign with their own key
+        let mut tx = spend(&coinbase, &owner, &thief.get_address(), SUBSIDY);
+        tx.sign_with_prev_txs(&prev_txs, thief.get_pkcs8());
+        assert!(!tx.verify_with_prev_txs(&prev_txs));
+    }
+
+    #[test]
+    fn own_key_cannot_spend_someone_elses_output() {
+        let owner = Wallet::new();
+        let thief = Wallet::new();
+        let coinbase = Transaction::new_coinbase_tx(&owner.get_address());
+        let prev_txs = prev_txs_of(&coinbase);
+
+        // A valid signature, but by a key the output isn't locked to
+        let mut tx = spend(&coinbase, &thief, &thief.get_address(), SUBSIDY);
+        tx.sign_with_prev_txs(&prev_txs, thief.get_pkcs8());
+        assert!(!tx.verify_with_prev_txs(&prev_txs));
+
+        let outputs = vec![TXOutput::new(SUBSIDY, &thief.get_address())];
+        let tx = Transaction::new_test_spend(&coinbase, 0, &thief, outputs);
+        assert!(!tx.verify_with_prev_txs(&prev_txs));
+    }
+
+    #[test]
+    fn tampered_signature_fails_verification() {
+        let sender = Wallet::new();
+        let receiver = Wallet::new();
+        let coinbase = Transaction::new_coinbase_tx(&sender.get_address());
+        let prev_txs = prev_txs_of(&coinbase);
+
+        let mut tx = spend(&coinbase, &sender, &receiver.get_address(), SUBSIDY);
+        tx.sign_with_prev_txs(&prev_txs, sender.get_pkcs8());
+        tx.vin[0].signature[0] ^= 0xff;
+        assert!(!tx.verify_with_prev_txs(&prev_txs));
+    }
+}
@@ -1,4 +1,33 @@
-use ring::signature::ECDSA_P256_SHA256_FIXED;
+use ring::{
+    digest::{Context, SHA256},
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use ripemd::{Digest, Ripemd160};
+
+pub fn sha256_digest(data: &[u8]) -> Vec<u8> {
+    let mut context = Context::new(&SHA256);
+    context.update(data);
+    context.finish().as_ref().to_vec()
+}
+
+pub fn ripemd160_digest(data: &[u8]) -> Vec<u8> {
+    let mut ripemd160 = Ripemd160::new();
+    ripemd160.update(data);
+    ripemd160.finalize().to_vec()
+}
+
+pub fn new_key_pair() -> Vec<u8> {
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+    pkcs8.as_ref().to_vec()
+}
+
+pub fn ecdsa_p256_sha256_sign_digest(pkcs8: &[u8], message: &[u8]) -> Vec<u8> {
+    let rng = SystemRandom::new();
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng).unwrap();
+    key_pair.sign(&rng, message).unwrap().as_ref().to_vec()
+}
 
 pub fn ecdsa_p256_sha256_sign_verify(public_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
     let peer_public_key =
@@ -6,6 +35,17 @@ pub fn ecdsa_p256_sha256_sign_verify(public_key: &[u8], signature: &[u8], messag
     peer_public_key.verify(message, signature.as_ref()).is_ok()
 }
 
+pub fn base58_encode(data: &[u8]) -> String {
+    bs58::encode(data).into_string()
+}
+
 pub fn base58_decode(data: &str) -> Vec<u8> {
     bs58::decode(data).into_vec().unwrap()
 }
+
+/// Random 64-bit value, e.g. to match a pong to its ping
+pub fn new_nonce() -> u64 {
+    let mut bytes = [0; 8];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    u64::from_be_bytes(bytes)
+}
@@ -1,10 +1,80 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
 
-use crate::{block::Block, blockchain::Blockchain, transaction::TXOutput};
+use crate::{
+    block::Block,
+    blockchain::{BlockValidationError, Blockchain},
+    transaction::TXOutput,
+};
 
+/// Keyed by outpoint, see `outpoint_key`
 const UTXO_TREE: &str = "chainstate";
+/// Secondary index keyed by `pub_key_hash || outpoint_key`, with empty values, so an
+/// address's coins can be found without scanning `chainstate`
+const ADDRESS_INDEX_TREE: &str = "addressindex";
+/// For every connected block, the entries its inputs spent, so `revert` can restore them
+const UNDO_TREE: &str = "undo";
+
+const VOUT_LEN: usize = 8;
+
+/// An unspent output together with where it was created
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UTXOEntry {
+    output: TXOutput,
+    height: usize,
+    is_coinbase: bool,
+}
+
+impl UTXOEntry {
+    pub fn new(output: TXOutput, height: usize, is_coinbase: bool) -> UTXOEntry {
+        UTXOEntry {
+            output,
+            height,
+            is_coinbase,
+        }
+    }
+
+    pub fn get_output(&self) -> &TXOutput {
+        &self.output
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.is_coinbase
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("unable to serialize UTXOEntry")
+    }
+
+    fn deserialize(bytes: &[u8]) -> UTXOEntry {
+        bincode::deserialize(bytes).expect("unable to deserialize UTXOEntry")
+    }
+}
+
+/// `txid || vout`, with `vout` as a big-endian u64 so a transaction's outputs sort together
+pub fn outpoint_key(txid: &[u8], vout: usize) -> Vec<u8> {
+    let mut key = txid.to_vec();
+    key.extend((vout as u64).to_be_bytes());
+    key
+}
+
+fn split_outpoint_key(key: &[u8]) -> (&[u8], usize) {
+    let (txid, vout_bytes) = key.split_at(key.len() - VOUT_LEN);
+    let vout = u64::from_be_bytes(vout_bytes.try_into().unwrap());
+    (txid, vout as usize)
+}
+
+fn address_index_key(pub_key_hash: &[u8], outpoint: &[u8]) -> Vec<u8> {
+    let mut key = pub_key_hash.to_vec();
+    key.extend(outpoint);
+    key
+}
 
 pub struct UTXOSet {
     blockchain: Blockchain,
@@ -26,94 +96,262 @@ impl UTXOSet {
     ) -> (i32, HashMap<String, Vec<usize>>) {
         let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
         let mut accumulated = 0;
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        for item in utxo_tree.iter() {
-            let (k, v) = item.unwrap();
-            let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice())
-                .expect("unable to deserialize TXOutput");
-            for (idx, out) in outs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += out.get_value();
-                    let outs = unspent_outputs.entry(txid_hex.clone()).or_insert(vec![]);
-                    outs.push(idx);
-                } else {
-                    unspent_outputs.insert(txid_hex.clone(), vec![idx]);
-                }
+        for (outpoint, entry) in self.find_address_entries(pub_key_hash) {
+            if accumulated >= amount {
+                break;
             }
+            let (txid, vout) = split_outpoint_key(outpoint.as_slice());
+            accumulated += entry.output.get_value();
+            let outs = unspent_outputs.entry(HEXLOWER.encode(txid)).or_default();
+            outs.push(vout);
         }
 
         (accumulated, unspent_outputs)
     }
 
     pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TXOutput> {
+        self.find_address_entries(pub_key_hash)
+            .into_iter()
+            .map(|(_, entry)| entry.output)
+            .collect()
+    }
+
+    /// Unspent outputs locked to `pub_key_hash`, read through the address index
+    fn find_address_entries(&self, pub_key_hash: &[u8]) -> Vec<(Vec<u8>, UTXOEntry)> {
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        let mut utxos = vec![];
-        for item in utxo_tree.iter() {
-            let (_, v) = item.unwrap();
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice())
-                .expect("unable to deserialize TXOutput");
-            for out in outs {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out);
-                }
+        let address_tree = db.open_tree(ADDRESS_INDEX_TREE).unwrap();
+        let mut entries = vec![];
+        for item in address_tree.scan_prefix(pub_key_hash) {
+            let (k, _) = item.unwrap();
+            let outpoint = k[pub_key_hash.len()..].to_vec();
+            let entry_bytes = utxo_tree
+                .get(outpoint.as_slice())
+                .unwrap()
+                .expect("address index matches the UTXO set");
+            let entry = UTXOEntry::deserialize(entry_bytes.as_ref());
+            // Guards against a prefix match on a longer hash
+            if entry.output.is_locked_with_key(pub_key_hash) {
+                entries.push((outpoint, entry));
             }
         }
-        utxos
+        entries
     }
 
+    /// Looks up a single unspent output
+    pub fn get_utxo(&self, txid: &[u8], vout: usize) -> Option<UTXOEntry> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        utxo_tree
+            .get(outpoint_key(txid, vout))
+            .unwrap()
+            .map(|v| UTXOEntry::deserialize(v.as_ref()))
+    }
+
+    /// Number of distinct transactions that still have unspent outputs
     pub fn count_transactions(&self) -> i32 {
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        utxo_tree.len() as i32
+        let mut txids = HashSet::new();
+        for item in utxo_tree.iter() {
+            let (k, _) = item.unwrap();
+            let (txid, _) = split_outpoint_key(k.as_ref());
+            txids.insert(txid.to_vec());
+        }
+        txids.len() as i32
     }
 
     pub fn reindex(&self) {
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        let _ = utxo_tree.clear().unwrap();
+        let address_tree = db.open_tree(ADDRESS_INDEX_TREE).unwrap();
+        utxo_tree.clear().unwrap();
+        address_tree.clear().unwrap();
 
         let utxo_map = self.blockchain.find_utxo();
-        for (txid_hex, outs) in &utxo_map {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-            let value = bincode::serialize(outs).unwrap();
-            let _ = utxo_tree.insert(txid.as_slice(), value).unwrap();
+        for (key, entry) in &utxo_map {
+            self.insert_entry(key.as_slice(), entry);
         }
     }
 
-    pub fn update(&self, block: &Block) {
+    /// Adds an output to `chainstate` and the address index
+    fn insert_entry(&self, outpoint: &[u8], entry: &UTXOEntry) {
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let address_tree = db.open_tree(ADDRESS_INDEX_TREE).unwrap();
+        let _ = utxo_tree.insert(outpoint, entry.serialize()).unwrap();
+        let address_key = address_index_key(entry.output.get_pub_key_hash(), outpoint);
+        let _ = address_tree.insert(address_key, vec![]).unwrap();
+    }
+
+    /// Removes an output from `chainstate` and the address index
+    fn remove_entry(&self, outpoint: &[u8]) -> Option<UTXOEntry> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let address_tree = db.open_tree(ADDRESS_INDEX_TREE).unwrap();
+        let entry = utxo_tree
+            .remove(outpoint)
+            .unwrap()
+            .map(|v| UTXOEntry::deserialize(v.as_ref()))?;
+        let address_key = address_index_key(entry.output.get_pub_key_hash(), outpoint);
+        let _ = address_tree.remove(address_key).unwrap();
+        Some(entry)
+    }
+
+    /// Spends the block's inputs and adds its outputs, recording undo data for
+    /// `revert`. Fails, leaving the set untouched, if an input is not unspent.
+    pub fn update(&self, block: &Block) -> Result<(), BlockValidationError> {
+        self.check_inputs(block)?;
+        let db = self.blockchain.get_db();
+        let mut undo: Vec<(Vec<u8>, UTXOEntry)> = vec![];
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                for vin in tx.get_vin() {
+                    let key = outpoint_key(vin.get_txid(), vin.get_vout());
+                    let entry = self
+                        .remove_entry(key.as_slice())
+                        .expect("inputs were checked");
+                    undo.push((key, entry));
+                }
+            }
+            for (idx, out) in tx.get_vout().iter().enumerate() {
+                let entry = UTXOEntry::new(out.clone(), block.get_height(), tx.is_coinbase());
+                self.insert_entry(outpoint_key(tx.get_id(), idx).as_slice(), &entry);
+            }
+        }
+
+        let undo_tree = db.open_tree(UNDO_TREE).unwrap();
+        let undo_bytes = bincode::serialize(&undo).expect("unable to serialize undo data");
+        let _ = undo_tree.insert(block.get_hash(), undo_bytes).unwrap();
+        Ok(())
+    }
+
+    /// Checks that every input of the block spends an output that is unspent,
+    /// either in the set or created earlier in the block, and is spent only once
+    fn check_inputs(&self, block: &Block) -> Result<(), BlockValidationError> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let mut created: HashSet<Vec<u8>> = HashSet::new();
+        let mut spent: HashSet<Vec<u8>> = HashSet::new();
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in tx.get_vin() {
-                    let mut updated_outs = vec![];
-                    let outs_bytes = utxo_tree.get(vin.get_txid()).unwrap().unwrap();
-                    let outs: Vec<TXOutput> =
-                        bincode::deserialize(&outs_bytes).expect("unable to deserialize TXOutput");
-                    for (idx, out) in outs.iter().enumerate() {
-                        if idx != vin.get_vout() {
-                            updated_outs.push(out.clone());
-                        }
+                    let key = outpoint_key(vin.get_txid(), vin.get_vout());
+                    let txid = HEXLOWER.encode(vin.get_txid());
+                    if !spent.insert(key.clone()) {
+                        return Err(BlockValidationError::DoubleSpend {
+                            txid,
+                            vout: vin.get_vout(),
+                        });
                     }
-                    if updated_outs.is_empty() {
-                        let _ = utxo_tree.remove(vin.get_txid()).unwrap();
-                    } else {
-                        let outs_bytes = bincode::serialize(&updated_outs)
-                            .expect("unable to serialize TXOutput");
-                        utxo_tree.insert(vin.get_txid(), outs_bytes).unwrap();
+                    if !created.contains(&key) && !utxo_tree.contains_key(&key).unwrap() {
+                        return Err(BlockValidationError::MissingInput {
+                            txid,
+                            vout: vin.get_vout(),
+                        });
                     }
                 }
             }
-            let mut new_outputs = vec![];
-            for out in tx.get_vout() {
-                new_outputs.push(out.clone());
+            for idx in 0..tx.get_vout().len() {
+                created.insert(outpoint_key(tx.get_id(), idx));
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes `update` for a block at the tip: puts back the outputs it spent and drops
+    /// the ones it created. Returns false when the block has no undo data, in which
+    /// case only `reindex` can rebuild the set.
+    pub fn revert(&self, block: &Block) -> bool {
+        let db = self.blockchain.get_db();
+        let undo_tree = db.open_tree(UNDO_TREE).unwrap();
+        let undo_bytes = match undo_tree.get(block.get_hash()).unwrap() {
+            Some(undo_bytes) => undo_bytes,
+            None => return false,
+        };
+        let undo: Vec<(Vec<u8>, UTXOEntry)> =
+            bincode::deserialize(&undo_bytes).expect("unable to deserialize undo data");
+
+        for (key, entry) in undo {
+            self.insert_entry(key.as_slice(), &entry);
+        }
+        // Removing created outputs last also drops any the block spent itself
+        for tx in block.get_transactions() {
+            for idx in 0..tx.get_vout().len() {
+                self.remove_entry(outpoint_key(tx.get_id(), idx).as_slice());
             }
-            let outs_bytes =
-                bincode::serialize(&new_outputs).expect("unable to serialize TXOutput");
-            let _ = utxo_tree.insert(tx.get_id(), outs_bytes).unwrap();
         }
+        let _ = undo_tree.remove(block.get_hash()).unwrap();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        transaction::{Transaction, SUBSIDY},
+        wallet::{hash_pub_key, Wallet},
+    };
+
+    fn snapshot(blockchain: &Blockchain, tree_name: &str) -> Vec<(sled::IVec, sled::IVec)> {
+        let tree = blockchain.get_db().open_tree(tree_name).unwrap();
+        tree.iter().map(|item| item.unwrap()).collect()
+    }
+
+    #[test]
+    fn revert_restores_the_state_before_update() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let carol = Wallet::new();
+        let blockchain = Blockchain::create_temporary(&alice.get_address());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+        let chainstate_before = snapshot(&blockchain, UTXO_TREE);
+        let address_index_before = snapshot(&blockchain, ADDRESS_INDEX_TREE);
+
+        let genesis = blockchain
+            .get_block(blockchain.get_tip_hash().as_bytes())
+            .unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        let pay_bob = Transaction::new_test_spend(
+            genesis_coinbase,
+            0,
+            &alice,
+            vec![TXOutput::new(SUBSIDY, &bob.get_address())],
+        );
+        // Spends an output created earlier in the same block
+        let pay_carol = Transaction::new_test_spend(
+            &pay_bob,
+            0,
+            &bob,
+            vec![TXOutput::new(SUBSIDY, &carol.get_address())],
+        );
+        let coinbase = Transaction::new_coinbase_tx(&alice.get_address());
+        let block = Block::new_block(
+            genesis.get_hash().to_string(),
+            &[pay_bob, pay_carol, coinbase],
+            1,
+            genesis.get_bits(),
+            crate::current_timestamp(),
+        );
+
+        utxo_set.update(&block).unwrap();
+        assert!(utxo_set
+            .find_utxo(&hash_pub_key(bob.get_public_key()))
+            .is_empty());
+        assert_eq!(
+            utxo_set
+                .find_utxo(&hash_pub_key(carol.get_public_key()))
+                .len(),
+            1
+        );
+
+        assert!(utxo_set.revert(&block));
+        assert_eq!(snapshot(&blockchain, UTXO_TREE), chainstate_before);
+        assert_eq!(
+            snapshot(&blockchain, ADDRESS_INDEX_TREE),
+            address_index_before
+        );
     }
 }
@@ -1 +1,129 @@
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+const VERSION: u8 = 0x00;
+/// Length of a RIPEMD-160 pub key hash
+const PUB_KEY_HASH_LEN: usize = 20;
 pub const ADDRESS_CHECK_SUM_LEN: usize = 4;
+
+/// A single ECDSA P-256 keypair, stored as its PKCS#8 document
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Wallet {
+    pkcs8: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+impl Wallet {
+    pub fn new() -> Wallet {
+        let pkcs8 = utils::new_key_pair();
+        let rng = SystemRandom::new();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_slice(), &rng)
+                .unwrap();
+        let public_key = key_pair.public_key().as_ref().to_vec();
+        Wallet { pkcs8, public_key }
+    }
+
+    /// Base58Check address: version byte, pub key hash and a 4-byte checksum
+    pub fn get_address(&self) -> String {
+        let pub_key_hash = hash_pub_key(self.public_key.as_slice());
+        convert_address(pub_key_hash.as_slice())
+    }
+
+    pub fn get_public_key(&self) -> &[u8] {
+        self.public_key.as_slice()
+    }
+
+    pub fn get_pkcs8(&self) -> &[u8] {
+        self.pkcs8.as_slice()
+    }
+}
+
+/// RIPEMD-160 of the SHA-256 of the public key
+pub fn hash_pub_key(pub_key: &[u8]) -> Vec<u8> {
+    let pub_key_sha256 = utils::sha256_digest(pub_key);
+    utils::ripemd160_digest(pub_key_sha256.as_slice())
+}
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    let first_sha = utils::sha256_digest(payload);
+    let second_sha = utils::sha256_digest(first_sha.as_slice());
+    second_sha[0..ADDRESS_CHECK_SUM_LEN].to_vec()
+}
+
+/// Whether `address` is one `convert_address` could have produced: our version
+/// byte, a 20-byte pub key hash and a matching checksum
+pub fn validate_address(address: &str) -> bool {
+    let payload = match bs58::decode(address).into_vec() {
+        Ok(payload) => payload,
+        Err(_) => return false,
+    };
+    if payload.len() != 1 + PUB_KEY_HASH_LEN + ADDRESS_CHECK_SUM_LEN || payload[0] != VERSION {
+        return false;
+    }
+    let (versioned_hash, actual_checksum) = payload.split_at(1 + PUB_KEY_HASH_LEN);
+    actual_checksum.eq(checksum(versioned_hash).as_slice())
+}
+
+pub fn convert_address(pub_key_hash: &[u8]) -> String {
+    let mut payload = vec![VERSION];
+    payload.extend(pub_key_hash);
+    let checksum = checksum(payload.as_slice());
+    payload.extend(checksum.as_slice());
+    utils::base58_encode(payload.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Base58Check-encodes `payload` with a valid checksum
+    fn encode_with_checksum(mut payload: Vec<u8>) -> String {
+        let checksum = checksum(payload.as_slice());
+        payload.extend(checksum);
+        utils::base58_encode(payload.as_slice())
+    }
+
+    #[test]
+    fn address_round_trips() {
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        assert!(validate_address(address.as_str()));
+        let payload = utils::base58_decode(address.as_str());
+        let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+        assert_eq!(pub_key_hash, hash_pub_key(wallet.get_public_key()));
+        assert_eq!(convert_address(pub_key_hash), address);
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        let address = Wallet::new().get_address();
+        let mut payload = utils::base58_decode(address.as_str());
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        assert!(!validate_address(utils::base58_encode(&payload).as_str()));
+        assert!(!validate_address("not base58: 0OIl"));
+        assert!(!validate_address(""));
+    }
+
+    #[test]
+    fn wrong_version_or_hash_length_is_rejected() {
+        let pub_key_hash = hash_pub_key(Wallet::new().get_public_key());
+        let mut wrong_version = vec![0x05];
+        wrong_version.extend(&pub_key_hash);
+        assert!(!validate_address(
+            encode_with_checksum(wrong_version).as_str()
+        ));
+
+        for len in [0, 1, PUB_KEY_HASH_LEN - 1, PUB_KEY_HASH_LEN + 1] {
+            let mut payload = vec![VERSION];
+            payload.extend(vec![7; len]);
+            assert!(!validate_address(encode_with_checksum(payload).as_str()));
+        }
+    }
+}
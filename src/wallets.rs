@@ -0,0 +1,69 @@
+use std::{
+    collections::HashMap,
+    env::current_dir,
+    fs::{self, OpenOptions},
+    io::{BufWriter, Write},
+};
+
+use crate::wallet::Wallet;
+
+/// Kept next to the `data` sled directory so keys survive between runs
+pub const WALLET_FILE: &str = "wallet.dat";
+
+pub struct Wallets {
+    wallets: HashMap<String, Wallet>,
+}
+
+impl Wallets {
+    pub fn new() -> Wallets {
+        let mut wallets = Wallets {
+            wallets: HashMap::new(),
+        };
+        wallets.load_from_file();
+        wallets
+    }
+
+    pub fn create_wallet(&mut self) -> String {
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        self.wallets.insert(address.clone(), wallet);
+        self.save_to_file();
+        address
+    }
+
+    pub fn get_addresses(&self) -> Vec<String> {
+        let mut addresses = vec![];
+        for address in self.wallets.keys() {
+            addresses.push(address.clone());
+        }
+        addresses
+    }
+
+    pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
+        self.wallets.get(address)
+    }
+
+    pub fn load_from_file(&mut self) {
+        let path = current_dir().unwrap().join(WALLET_FILE);
+        if !path.exists() {
+            return;
+        }
+        let buf = fs::read(path).expect("unable to read wallet.dat");
+        let wallets = bincode::deserialize(&buf[..]).expect("unable to deserialize file data");
+        self.wallets = wallets;
+    }
+
+    fn save_to_file(&self) {
+        let path = current_dir().unwrap().join(WALLET_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .expect("unable to open wallet.dat");
+        let mut writer = BufWriter::new(file);
+        let wallets_bytes = bincode::serialize(&self.wallets).expect("unable to serialize wallets");
+        writer.write_all(wallets_bytes.as_slice()).unwrap();
+        let _ = writer.flush();
+    }
+}